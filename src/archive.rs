@@ -0,0 +1,755 @@
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use clap::{Parser, ValueEnum};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use walkdir::WalkDir;
+
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+use zip::read::ZipFile;
+use zip::result::{ZipError, ZipResult};
+use zip::write::FileOptions;
+
+use crate::filter::{ignore_file, Filter};
+use crate::sparse::ExtentWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// no compression
+    Store,
+    /// deflate, gzip for tar archives, readable by any zip or tar tool
+    Deflate,
+    /// bzip2, smaller than deflate but slower
+    Bzip2,
+    /// zstd, fast with good ratios
+    Zstd,
+    /// lz4, the fastest but larger; tar archives only
+    Lz4,
+    /// xz, the smallest but slowest; tar archives only
+    Xz,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Symlinks {
+    /// store links as links
+    Store,
+    /// store what links point to, skipping links that loop
+    Follow,
+    /// leave links out
+    Skip,
+}
+
+/// How a snapshot's files are packed into its archive. Recorded in the snapshot's manifest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// zip, compressing every file on its own so single files can be restored quickly
+    #[default]
+    Zip,
+    /// pax tar, compressed as a whole and keeping owners, modes, mtimes, links and xattrs
+    Tar,
+}
+
+/// How the entries of an archive are compressed. Recorded in the snapshot's manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Codec specific level, or the codec's default if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    pub const STORE: Compression = Compression { codec: Codec::Store, level: None };
+
+    pub fn new(format: Format, codec: Codec, level: Option<i32>) -> Result<Self, String> {
+        let range = match codec {
+            Codec::Store | Codec::Lz4 => None,
+            Codec::Deflate | Codec::Xz => Some(0..=9),
+            Codec::Bzip2 => Some(1..=9),
+            Codec::Zstd => Some(-7..=22),
+        };
+        let name = codec.to_possible_value().unwrap();
+        match (level, range) {
+            _ if format == Format::Zip && matches!(codec, Codec::Lz4 | Codec::Xz) => {
+                Err(format!("{} compression needs --format tar", name.get_name()))
+            }
+            (Some(_), None) => Err(format!("{} has no compression levels", name.get_name())),
+            (Some(level), Some(range)) if !range.contains(&level) => Err(format!(
+                "{} compression levels range from {} to {}",
+                name.get_name(),
+                range.start(),
+                range.end(),
+            )),
+            _ => Ok(Compression { codec, level }),
+        }
+    }
+
+    fn method(&self) -> CompressionMethod {
+        match self.codec {
+            Codec::Store => CompressionMethod::Stored,
+            Codec::Deflate => CompressionMethod::Deflated,
+            Codec::Bzip2 => CompressionMethod::Bzip2,
+            Codec::Zstd => CompressionMethod::Zstd,
+            Codec::Lz4 | Codec::Xz => unreachable!("{:?} is only used for tar archives", self.codec),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { codec: Codec::Deflate, level: None }
+    }
+}
+
+/// Formats that are already compressed, so compressing them again only wastes CPU.
+const INCOMPRESSIBLE_EXTENSIONS: &str = "7z,avif,br,bz2,docx,flac,gif,gz,heic,jar,jpeg,jpg,lz4,m4a,mkv,mov,mp3,mp4,\
+    ogg,pdf,png,rar,tgz,webm,webp,xlsx,xz,zip,zst";
+
+/// File type bits of a unix mode, and their value for symlinks.
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Bytes at the start of a file its entropy is estimated from.
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Detection {
+    /// compress every file
+    Off,
+    /// by file extension
+    Extension,
+    /// by the entropy of the file's first block
+    Entropy,
+    /// by extension, then by entropy
+    Both,
+}
+
+/// How files that wouldn't shrink are recognized, so they can be stored without compression.
+#[derive(Parser, Debug)]
+pub struct IncompressibleParams {
+    #[arg(long = "incompressible", value_enum, default_value_t = Detection::Both, help = "how files stored without compression because they wouldn't shrink are detected")]
+    pub detection: Detection,
+    #[arg(long = "incompressible-extensions", value_delimiter = ',', default_value = INCOMPRESSIBLE_EXTENSIONS, help = "comma separated extensions of files that are already compressed")]
+    pub extensions: Vec<String>,
+    #[arg(long = "entropy-threshold", default_value_t = 7.5, help = "bits per byte of entropy above which a file's first block counts as incompressible")]
+    pub entropy_threshold: f64,
+}
+
+impl IncompressibleParams {
+    /// Whether the file `name` should be stored uncompressed. Sampling leaves `file` where it
+    /// started.
+    fn check(&self, name: &str, file: &mut File, buffer: &mut [u8]) -> io::Result<bool> {
+        let by_extension = || {
+            Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+        };
+        Ok(match self.detection {
+            Detection::Off => false,
+            Detection::Extension => by_extension(),
+            Detection::Entropy => self.high_entropy(file, buffer)?,
+            Detection::Both => by_extension() || self.high_entropy(file, buffer)?,
+        })
+    }
+
+    fn high_entropy(&self, file: &mut File, buffer: &mut [u8]) -> io::Result<bool> {
+        let sample_size = buffer.len().min(ENTROPY_SAMPLE_SIZE);
+        let sample = &mut buffer[..sample_size];
+        let mut len = 0;
+        while len < sample.len() {
+            match file.read(&mut sample[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(entropy(&sample[..len]) > self.entropy_threshold)
+    }
+}
+
+/// Shannon entropy of `data` in bits per byte, from 0 for constant data to 8 for random data.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u64; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// What went into an archive, reported once a backup is done.
+#[derive(Debug, Default)]
+pub struct ArchiveSummary {
+    pub files: u64,
+    pub bytes: u64,
+    /// Files stored without compression because they were detected as incompressible.
+    pub stored_files: u64,
+    pub stored_bytes: u64,
+}
+
+impl ArchiveSummary {
+    pub fn add(&mut self, size: u64, stored: bool) {
+        self.files += 1;
+        self.bytes += size;
+        if stored {
+            self.stored_files += 1;
+            self.stored_bytes += size;
+        }
+    }
+}
+
+/// How an archive is written.
+pub struct ArchiveOptions<'a> {
+    pub format: Format,
+    pub compression: Compression,
+    /// Picks out files stored without compression, in zip archives.
+    pub incompressible: &'a IncompressibleParams,
+    /// Number of files compressed at once, in zip archives.
+    pub threads: usize,
+    pub walk: &'a WalkOptions,
+    /// Maximum bytes of file data held in memory at once, shared between the threads.
+    pub buffer_size: usize,
+}
+
+/// Archives the tree under `src` into `dst`. `select` is called with the archive name and
+/// metadata of every file and directory; entries it returns `false` for are left out. Files
+/// are stored whole, holes included, so that any unzip extracts them as they were.
+pub fn zip_dir<T, F>(src: &str, dst: T, options: &ArchiveOptions, mut select: F) -> ZipResult<ArchiveSummary>
+where
+    T: Write + Seek,
+    F: FnMut(&str, &Path, &Metadata) -> io::Result<bool>,
+{
+    if !Path::new(src).is_dir() {
+        return Err(ZipError::FileNotFound);
+    }
+
+    let mut zip = ZipWriter::new(dst);
+    let mut summary = ArchiveSummary::default();
+    if options.threads > 1 {
+        zip_parallel(src, &mut zip, options, &mut summary, select)?;
+    } else {
+        let mut buffer = vec![0; options.buffer_size.max(1)];
+        walk_tree(src, options.walk, |name, path, metadata| {
+            let Some(source) = open_source(path, metadata, false)? else {
+                return Ok(());
+            };
+            if !select(name, path, metadata)? {
+                return Ok(());
+            }
+
+            if let Some(file) = source.file {
+                let stored = add_file(&mut zip, name, file, metadata, options, &mut buffer)?;
+                summary.add(metadata.len(), stored);
+            } else if metadata.is_dir() {
+                zip.add_directory(name, entry_options(options.compression, metadata))?;
+            } else if let Some(target) = source.target {
+                zip.add_symlink(name, target, entry_options(Compression::STORE, metadata))?;
+            }
+            Ok(())
+        })?;
+    }
+    zip.finish()?;
+
+    Ok(summary)
+}
+
+/// Options of an entry with the mode and modification time, in UTC, of the file or directory
+/// `metadata` belongs to. Zip entries only keep permission bits, and times to two seconds.
+fn entry_options(compression: Compression, metadata: &Metadata) -> FileOptions {
+    let modified = metadata.modified().map(OffsetDateTime::from).ok();
+    FileOptions::default()
+        .compression_method(compression.method())
+        .compression_level(compression.level)
+        .unix_permissions(file_mode(metadata))
+        .last_modified_time(modified.and_then(|modified| DateTime::try_from(modified).ok()).unwrap_or_default())
+}
+
+/// Sets the owner and group, if given, the extended attributes, the mode, if known, and the
+/// modification time of a restored file, directory or symlink.
+/// Symlinks have no mode of their own. Changing the owner clears file capabilities and setuid
+/// bits, so it goes first, and attributes go before the mode, since a read-only mode keeps
+/// them from being set.
+pub fn set_metadata(
+    path: &Path,
+    owner: Option<(u32, u32)>,
+    mode: Option<u32>,
+    modified: SystemTime,
+    xattrs: &[(String, Vec<u8>)],
+) -> io::Result<()> {
+    chown(path, owner)?;
+    write_xattrs(path, xattrs)?;
+    let metadata = std::fs::symlink_metadata(path)?;
+    let accessed = FileTime::from_last_access_time(&metadata);
+    filetime::set_symlink_file_times(path, accessed, FileTime::from_system_time(modified))?;
+    match mode {
+        Some(mode) if !metadata.file_type().is_symlink() => set_mode(path, mode),
+        _ => Ok(()),
+    }
+}
+
+/// Restores a zip entry to `output` as a file, or as a symlink if that's what it was stored
+/// as, with `owner`, `mode` or else the entry's own, which lacks setuid, setgid and sticky
+/// bits, `modified` as its modification time and `xattrs`. A sparse file, stored whole, gets
+/// holes again everywhere outside its data regions `extents`.
+pub fn extract_entry(
+    entry: &mut ZipFile,
+    output: &Path,
+    owner: Option<(u32, u32)>,
+    mode: Option<u32>,
+    modified: SystemTime,
+    xattrs: &[(String, Vec<u8>)],
+    extents: &[(u64, u64)],
+) -> io::Result<()> {
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if entry.unix_mode().is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+        let mut target = String::new();
+        entry.read_to_string(&mut target)?;
+        create_symlink(&target, output)?;
+    } else {
+        remove_symlink(output)?;
+        let mut writer = ExtentWriter::whole(File::create(output)?, extents.to_vec());
+        io::copy(entry, &mut writer)?;
+        writer.finish()?;
+    }
+    set_metadata(output, owner, mode.or(entry.unix_mode()), modified, xattrs)
+}
+
+/// Creates a symlink to `target` at `path`, replacing whatever is there other than a directory.
+pub fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+    symlink(target, path)
+}
+
+/// Creates a hardlink at `path` to the restored file `target`, replacing whatever is there
+/// other than a directory.
+pub fn create_hardlink(target: &Path, path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => std::fs::remove_file(path)?,
+        _ => {}
+    }
+    std::fs::hard_link(target, path)
+}
+
+/// Where the entry `name` of a snapshot is restored to under `root`, to be called right
+/// before restoring it. Names come from the bucket, where anyone able to write can forge
+/// them, so names that aren't plain relative paths, and names below a symlink, such as one
+/// restored earlier, are refused, since they could point outside `root`.
+pub fn entry_path(root: &Path, name: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(name);
+    if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        let message = format!("refusing to restore {name:?}, which points outside the restore directory");
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let mut ancestor = root.to_path_buf();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        match std::fs::symlink_metadata(&ancestor) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let message = format!("refusing to restore {name:?}, which is below the symlink {}", ancestor.display());
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(root.join(relative))
+}
+
+/// Removes a symlink at `path`, so writing a file there doesn't write through it.
+pub fn remove_symlink(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, path: &Path) -> io::Result<()> {
+    let message = format!("{} can't be restored as a symlink on this platform", path.display());
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+/// Mode of the file `metadata` belongs to, with its file type bits.
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions())
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(metadata: &Metadata) -> u32 {
+    if metadata.is_dir() { 0o755 } else { 0o644 }
+}
+
+/// Gives `path`, or a symlink itself, to the user and group ids of `owner`, if given.
+#[cfg(unix)]
+fn chown(path: &Path, owner: Option<(u32, u32)>) -> io::Result<()> {
+    match owner {
+        Some((uid, gid)) => std::os::unix::fs::lchown(path, Some(uid), Some(gid)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path, _owner: Option<(u32, u32)>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Extended attributes of `path`, not following symlinks. On Linux these include POSIX ACLs,
+/// as `system.posix_acl_access` and `system.posix_acl_default`, and file capabilities, as
+/// `security.capability`. Filesystems without extended attributes, like vfat, have none, and
+/// attributes that can't be read are skipped with a warning.
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error().is_some_and(|code| code == libc::ENOTSUP || code == libc::EOPNOTSUPP) => {
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = Vec::new();
+    for name in names {
+        let Some(key) = name.to_str() else { continue };
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => xattrs.push((key.to_string(), value)),
+            Ok(None) => {}
+            Err(e) => eprintln!("skipping extended attribute {key} of {}: {e}", path.display()),
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(unix))]
+pub fn read_xattrs(_path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// Sets extended attributes read by `read_xattrs` on a restored entry. Most of them, like file
+/// capabilities, can only be set by root.
+#[cfg(unix)]
+fn write_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    for (key, value) in xattrs {
+        xattr::set(path, key, value).map_err(|e| {
+            let message = format!("can't set {key} on {}: {e}, restore with --no-xattrs to leave them out", path.display());
+            io::Error::new(e.kind(), message)
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_xattrs(_path: &Path, _xattrs: &[(String, Vec<u8>)]) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes the open file `f` as the entry `name`, and returns whether it was stored without
+/// compression for being incompressible.
+fn add_file<T: Write + Seek>(
+    zip: &mut ZipWriter<T>,
+    name: &str,
+    mut f: File,
+    metadata: &Metadata,
+    options: &ArchiveOptions,
+    buffer: &mut [u8],
+) -> ZipResult<bool> {
+    let size = metadata.len();
+    let stored = options.compression.codec != Codec::Store && options.incompressible.check(name, &mut f, buffer)?;
+    let compression = match stored {
+        true => Compression::STORE,
+        false => options.compression,
+    };
+    zip.start_file(name, entry_options(compression, metadata).large_file(size >= u32::MAX as u64))?;
+    copy_bounded(&mut f, zip, buffer)?;
+    Ok(stored)
+}
+
+/// An entry ready to go into the archive: a directory, a symlink, or a file already compressed
+/// into a single entry archive of its own.
+enum Ready {
+    Dir(String, FileOptions),
+    Symlink { name: String, target: String, options: FileOptions },
+    File { archive: File, size: u64, stored: bool },
+}
+
+/// Compresses files on `options.threads` threads, each into a temporary single entry
+/// archive, and splices the results into `zip` in the order the tree was walked, so the
+/// archive comes out the same as when compressing on one thread.
+fn zip_parallel<T, F>(
+    src: &str,
+    zip: &mut ZipWriter<T>,
+    options: &ArchiveOptions,
+    summary: &mut ArchiveSummary,
+    mut select: F,
+) -> ZipResult<()>
+where
+    T: Write + Seek,
+    F: FnMut(&str, &Path, &Metadata) -> io::Result<bool>,
+{
+    let buffer_size = (options.buffer_size / options.threads).max(1);
+    // Bounds the temporary archives waiting for an earlier, slower file to finish.
+    let max_pending = options.threads * 2;
+
+    let (jobs, job_receiver) = mpsc::channel::<(usize, String, File, Metadata)>();
+    let job_receiver = Mutex::new(job_receiver);
+    let (done, results) = mpsc::channel::<(usize, ZipResult<Ready>)>();
+    thread::scope(|scope| {
+        for _ in 0..options.threads {
+            let job_receiver = &job_receiver;
+            let done = done.clone();
+            scope.spawn(move || {
+                let mut buffer = vec![0; buffer_size];
+                loop {
+                    let job = job_receiver.lock().unwrap().recv();
+                    let Ok((index, name, file, metadata)) = job else { break };
+                    let result = compress_file(&name, file, &metadata, options, &mut buffer);
+                    if done.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(done);
+
+        let mut splicer = Splicer { zip, summary, next: 0, ready: BTreeMap::new() };
+        let receive = |splicer: &mut Splicer<T>| -> io::Result<()> {
+            let (index, result) = results
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "a compression thread stopped"))?;
+            splicer.insert(index, result?)
+        };
+        let mut queued = 0;
+        walk_tree(src, options.walk, |name, path, metadata| {
+            let Some(source) = open_source(path, metadata, false)? else {
+                return Ok(());
+            };
+            if !select(name, path, metadata)? {
+                return Ok(());
+            }
+
+            if let Some(file) = source.file {
+                while queued - splicer.next >= max_pending {
+                    receive(&mut splicer)?;
+                }
+                jobs.send((queued, name.to_string(), file, metadata.clone())).unwrap();
+            } else if metadata.is_dir() {
+                splicer.insert(queued, Ready::Dir(name.to_string(), entry_options(options.compression, metadata)))?;
+            } else if let Some(target) = source.target {
+                let options = entry_options(Compression::STORE, metadata);
+                splicer.insert(queued, Ready::Symlink { name: name.to_string(), target, options })?;
+            } else {
+                return Ok(());
+            }
+            queued += 1;
+            Ok(())
+        })?;
+        drop(jobs);
+        while splicer.next < queued {
+            receive(&mut splicer)?;
+        }
+        Ok(())
+    })
+}
+
+fn compress_file(
+    name: &str,
+    file: File,
+    metadata: &Metadata,
+    options: &ArchiveOptions,
+    buffer: &mut [u8],
+) -> ZipResult<Ready> {
+    let mut zip = ZipWriter::new(tempfile::tempfile()?);
+    let stored = add_file(&mut zip, name, file, metadata, options, buffer)?;
+    Ok(Ready::File { archive: zip.finish()?, size: metadata.len(), stored })
+}
+
+/// Writes entries into the archive in order as they become ready.
+struct Splicer<'a, T: Write + Seek> {
+    zip: &'a mut ZipWriter<T>,
+    summary: &'a mut ArchiveSummary,
+    next: usize,
+    ready: BTreeMap<usize, Ready>,
+}
+
+impl<T: Write + Seek> Splicer<'_, T> {
+    fn insert(&mut self, index: usize, entry: Ready) -> io::Result<()> {
+        self.ready.insert(index, entry);
+        while let Some(entry) = self.ready.remove(&self.next) {
+            match entry {
+                Ready::Dir(name, options) => self.zip.add_directory(name, options)?,
+                Ready::Symlink { name, target, options } => self.zip.add_symlink(name, target, options)?,
+                Ready::File { archive, size, stored } => {
+                    let mut archive = ZipArchive::new(archive)?;
+                    self.zip.raw_copy_file(archive.by_index_raw(0)?)?;
+                    self.summary.add(size, stored);
+                }
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
+
+/// How the tree under a backup's root is walked.
+pub struct WalkOptions {
+    pub symlinks: Symlinks,
+    /// Picks out the entries left out, along with everything below excluded directories.
+    pub filter: Filter,
+}
+
+/// Calls `visit` with the name relative to `src`, path and metadata of every file, directory
+/// and, unless following or skipping them, symlink below `src` that isn't excluded, parents
+/// before their contents. Entries that can't be read, like links that loop back to a
+/// directory they are in or files removed while the tree is walked, and entries whose names
+/// aren't valid unicode are skipped with a warning.
+pub fn walk_tree<F>(src: &str, options: &WalkOptions, mut visit: F) -> io::Result<()>
+where
+    F: FnMut(&str, &Path, &Metadata) -> io::Result<()>,
+{
+    let symlinks = options.symlinks;
+    let mut ignore_files = Vec::new();
+    let mut entries = WalkDir::new(src).follow_links(symlinks == Symlinks::Follow).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(Path::new(src)).display().to_string();
+                match e.loop_ancestor() {
+                    Some(ancestor) => eprintln!("skipping {path}: symlink loop back to {}", ancestor.display()),
+                    None => eprintln!("skipping {path}: {e}"),
+                }
+                continue;
+            }
+        };
+        // Only the ignore files of directories this entry is in still apply.
+        ignore_files.retain(|(depth, _)| *depth < entry.depth());
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+        let Some(name) = path.strip_prefix(Path::new(src)).unwrap().to_str() else {
+            eprintln!("skipping {}: its name is not valid unicode", path.display());
+            if metadata.is_dir() {
+                entries.skip_current_dir();
+            }
+            continue;
+        };
+        if !name.is_empty() {
+            if options.filter.excludes(path, metadata.is_dir(), &ignore_files) {
+                if metadata.is_dir() {
+                    entries.skip_current_dir();
+                }
+                continue;
+            }
+            if symlinks == Symlinks::Skip && metadata.file_type().is_symlink() {
+                continue;
+            }
+            visit(name, path, &metadata)?;
+        }
+        if metadata.is_dir() {
+            if let Some(ignore) = ignore_file(path) {
+                ignore_files.push((entry.depth(), ignore));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An entry as read before it's selected for storing, so one removed after the walk found it
+/// is left out rather than recorded without its contents. A file is held open, which keeps its
+/// data readable even if it's removed later.
+pub struct Source {
+    pub file: Option<File>,
+    pub target: Option<String>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Opens the file or reads the symlink target of the entry at `path`, and with `xattrs` its
+/// extended attributes, or returns `None` with a warning if it was removed since it was walked.
+pub fn open_source(path: &Path, metadata: &Metadata, xattrs: bool) -> io::Result<Option<Source>> {
+    let open = || -> io::Result<Source> {
+        Ok(Source {
+            file: metadata.is_file().then(|| File::open(path)).transpose()?,
+            target: metadata.file_type().is_symlink().then(|| link_target(path)).transpose()?,
+            xattrs: if xattrs { read_xattrs(path)? } else { Vec::new() },
+        })
+    };
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn_vanished(path);
+            Ok(None)
+        }
+        source => source.map(Some),
+    }
+}
+
+/// Warns that the entry at `path` is left out of the backup for having been removed while it
+/// was being backed up.
+pub fn warn_vanished(path: &Path) {
+    eprintln!("skipping {}: it was removed while being backed up", path.display());
+}
+
+/// What the symlink at `path` points to.
+pub fn link_target(path: &Path) -> io::Result<String> {
+    let target = std::fs::read_link(path)?;
+    target.into_os_string().into_string().map_err(|target| {
+        let message = format!("{} points to {}, which is not valid unicode", path.display(), Path::new(&target).display());
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// Copies `reader` into `writer` one `buffer`-sized chunk at a time, so memory use stays
+/// bounded by the buffer no matter how large the source is.
+pub fn copy_bounded<R: Read, W: Write>(reader: &mut R, writer: &mut W, buffer: &mut [u8]) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let n = match reader.read(buffer) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        total += n as u64;
+    }
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::BoxError;
+
+/// Settings read from the config file, used for anything not given as a flag or environment
+/// variable.
+///
+/// ```toml
+/// region = "eu-central-1"
+/// endpoint_url = "http://minio.local:9000"
+/// path_style = true
+/// profile = "backup"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub path_style: Option<bool>,
+    pub profile: Option<String>,
+}
+
+impl ConfigFile {
+    /// Reads `path`, or the default config file if no path is given. Only an explicitly
+    /// given file has to exist.
+    pub fn load(path: Option<&Path>) -> Result<Self, BoxError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| format!("invalid config file {}: {e}", path.display()).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(ConfigFile::default()),
+            Err(e) => Err(format!("can't read config file {}: {e}", path.display()).into()),
+        }
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("aws-backup").join("config.toml"))
+}
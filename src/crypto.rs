@@ -0,0 +1,691 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadInPlace, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use clap::Parser;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::BoxError;
+use crate::keyring::{list, unlock};
+use crate::storage::Storage;
+
+const MAGIC: &[u8; 8] = b"awsbkenc";
+const VERSION: u8 = 1;
+
+/// Plaintext bytes per segment.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+pub const SALT_SIZE: usize = 16;
+
+/// Stanza holding the file key wrapped with a key derived from a passphrase.
+const STANZA_PASSPHRASE: u8 = 1;
+/// Stanza holding the file key wrapped directly with a key from a key file or with the
+/// bucket's master key.
+const STANZA_KEY_FILE: u8 = 2;
+/// Stanza holding the file key wrapped for an X25519 recipient, next to the ephemeral public
+/// key the wrapping key was agreed with.
+const STANZA_X25519: u8 = 3;
+
+#[derive(Parser, Debug)]
+pub struct EncryptionParams {
+    #[arg(long = "passphrase", env = "AWS_BACKUP_PASSPHRASE", hide_env_values = true, help = "encrypt or decrypt with a key derived from this passphrase")]
+    passphrase: Option<String>,
+    #[arg(long = "key-file", conflicts_with = "passphrase", help = "encrypt or decrypt with the 32 byte key in this file, raw or hex encoded")]
+    key_file: Option<PathBuf>,
+    #[arg(long = "identity", help = "decrypt with the private key in this file, as written by keygen; may be repeated")]
+    identities: Vec<PathBuf>,
+}
+
+impl EncryptionParams {
+    /// The keys to encrypt or decrypt with, or `None` if stored objects aren't encrypted.
+    /// New objects are encrypted to `recipients` as well as to the key file or passphrase.
+    /// If the passphrase unlocks the bucket's master key, new objects are encrypted with the
+    /// master key instead, so they stay readable after passphrase changes. A passphrase that
+    /// unlocks none of the bucket's keys is an error, unless only `decrypting` objects, which
+    /// may have been encrypted with it on its own before the bucket had keys.
+    pub async fn keys(&self, storage: &Arc<dyn Storage>, recipients: &[String], decrypting: bool) -> Result<Option<Keys>, BoxError> {
+        let key = match (&self.passphrase, &self.key_file) {
+            (_, Some(key_file)) => Some(read_key_file(key_file)?),
+            (Some(passphrase), None) => match unlock(storage, passphrase).await? {
+                Some((_, master_key)) => Some(master_key),
+                None if !list(storage).await?.is_empty() => {
+                    if !decrypting {
+                        return Err("the passphrase doesn't unlock any of the bucket's keys".into());
+                    }
+                    eprintln!("the passphrase doesn't unlock any of the bucket's keys and is used on its own");
+                    None
+                }
+                None => None,
+            },
+            (None, None) => None,
+        };
+        let passphrase = self.passphrase.clone();
+        let recipients = recipients.iter().map(|key| parse_public_key(key)).collect::<Result<Vec<_>, _>>()?;
+        let identities = self.identities.iter().map(|path| read_identity(path)).collect::<Result<Vec<_>, _>>()?;
+        if passphrase.is_none() && key.is_none() && recipients.is_empty() && identities.is_empty() {
+            return Ok(None);
+        }
+        let salt = random_salt();
+        Ok(Some(Keys { passphrase, key, recipients, identities, salt, derived: Mutex::new(HashMap::new()) }))
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<[u8; KEY_SIZE], BoxError> {
+    let contents = fs::read(path)?;
+    let key = match std::str::from_utf8(&contents) {
+        Ok(text) if text.trim().len() == 2 * KEY_SIZE => hex::decode(text.trim())?,
+        _ => contents,
+    };
+    key.try_into().map_err(|_| format!("{} does not hold a {KEY_SIZE} byte key", path.display()).into())
+}
+
+fn parse_public_key(recipient: &str) -> Result<PublicKey, BoxError> {
+    let key: [u8; KEY_SIZE] = hex::decode(recipient)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| format!("{recipient} is not a public key"))?;
+    Ok(PublicKey::from(key))
+}
+
+/// Reads the private key from an identity file, skipping `#` comment lines.
+fn read_identity(path: &Path) -> Result<StaticSecret, BoxError> {
+    let contents = fs::read_to_string(path)?;
+    let key: [u8; KEY_SIZE] = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|line| hex::decode(line).ok())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| format!("{} does not hold a private key", path.display()))?;
+    Ok(StaticSecret::from(key))
+}
+
+/// Writes a new private key to the identity file `path`, readable only by its owner, and
+/// returns the matching public key to encrypt backups to.
+pub fn generate_identity(path: &Path) -> Result<String, BoxError> {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = hex::encode(PublicKey::from(&secret).as_bytes());
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "# public key: {public_key}")?;
+    writeln!(file, "{}", hex::encode(secret.to_bytes()))?;
+    Ok(public_key)
+}
+
+/// One way of recovering an object's file key, stored in its header.
+struct Stanza {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+/// The user's keys, able to wrap the file keys of new objects and unwrap those of stored ones.
+pub struct Keys {
+    passphrase: Option<String>,
+    /// A key from a key file or the bucket's master key, preferred over the passphrase.
+    key: Option<[u8; KEY_SIZE]>,
+    /// Public keys new objects are encrypted to, whose private keys are kept elsewhere.
+    recipients: Vec<PublicKey>,
+    identities: Vec<StaticSecret>,
+    /// Salt used to derive the passphrase key for objects encrypted by this run, so the
+    /// expensive derivation only happens once.
+    salt: [u8; SALT_SIZE],
+    /// Passphrase keys already derived, by salt.
+    derived: Mutex<HashMap<[u8; SALT_SIZE], [u8; KEY_SIZE]>>,
+}
+
+impl Keys {
+    /// Whether these keys can decrypt objects, rather than only encrypt them to recipients.
+    pub fn can_decrypt(&self) -> bool {
+        self.passphrase.is_some() || self.key.is_some() || !self.identities.is_empty()
+    }
+
+    /// Secret the names of chunks are keyed with, derived from the key or else the passphrase;
+    /// keys that only hold recipients and identities have none.
+    pub fn chunk_secret(&self) -> Result<Option<[u8; KEY_SIZE]>, BoxError> {
+        let key = match (&self.key, &self.passphrase) {
+            (Some(key), _) => *key,
+            (None, Some(passphrase)) => self.passphrase_key(passphrase, &[0; SALT_SIZE])?,
+            (None, None) => return Ok(None),
+        };
+        let mut secret = [0; KEY_SIZE];
+        Hkdf::<Sha256>::new(None, &key)
+            .expand(b"aws-backup chunk names", &mut secret)
+            .expect("key size is a valid HKDF output length");
+        Ok(Some(secret))
+    }
+
+    fn passphrase_key(&self, passphrase: &str, salt: &[u8; SALT_SIZE]) -> Result<[u8; KEY_SIZE], BoxError> {
+        let mut derived = self.derived.lock().unwrap();
+        if let Some(key) = derived.get(salt) {
+            return Ok(*key);
+        }
+        let key = derive_key(passphrase, salt)?;
+        derived.insert(*salt, key);
+        Ok(key)
+    }
+
+    /// Header of a new object named `name` whose data is encrypted with `file_key`. The name
+    /// is authenticated so a stored object can't be passed off as another one.
+    fn header(&self, name: &str, file_key: &[u8; KEY_SIZE]) -> Result<Vec<u8>, BoxError> {
+        let mut stanzas = Vec::new();
+        match (&self.key, &self.passphrase) {
+            (Some(key), _) => stanzas.push(Stanza { kind: STANZA_KEY_FILE, body: wrap(key, name, file_key)? }),
+            (None, Some(passphrase)) => {
+                let key = self.passphrase_key(passphrase, &self.salt)?;
+                let body = [self.salt.as_slice(), &wrap(&key, name, file_key)?].concat();
+                stanzas.push(Stanza { kind: STANZA_PASSPHRASE, body });
+            }
+            (None, None) => {}
+        }
+        for recipient in &self.recipients {
+            let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_public = PublicKey::from(&ephemeral);
+            let key = recipient_key(ephemeral.diffie_hellman(recipient).as_bytes(), &ephemeral_public, recipient);
+            let body = [ephemeral_public.as_bytes().as_slice(), &wrap(&key, name, file_key)?].concat();
+            stanzas.push(Stanza { kind: STANZA_X25519, body });
+        }
+        if stanzas.is_empty() {
+            return Err("encrypting needs a passphrase, key file or recipient".into());
+        }
+
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.push(stanzas.len() as u8);
+        for stanza in stanzas {
+            header.push(stanza.kind);
+            header.extend((stanza.body.len() as u16).to_be_bytes());
+            header.extend(stanza.body);
+        }
+        Ok(header)
+    }
+
+    /// Recovers the file key of the object named `name` from one of its header's stanzas.
+    fn file_key(&self, name: &str, stanzas: &[Stanza]) -> Result<[u8; KEY_SIZE], BoxError> {
+        if !self.can_decrypt() {
+            return Err(format!("{name} can't be decrypted without a passphrase, key file or identity").into());
+        }
+        for Stanza { kind, body } in stanzas {
+            let unwrapped = match *kind {
+                STANZA_PASSPHRASE if body.len() > SALT_SIZE && self.passphrase.is_some() => {
+                    let (salt, wrapped) = body.split_at(SALT_SIZE);
+                    let key = self.passphrase_key(self.passphrase.as_ref().unwrap(), salt.try_into().unwrap())?;
+                    unwrap(&key, name, wrapped)
+                }
+                STANZA_KEY_FILE => self.key.and_then(|key| unwrap(&key, name, body)),
+                STANZA_X25519 if body.len() > KEY_SIZE => {
+                    let (ephemeral_public, wrapped) = body.split_at(KEY_SIZE);
+                    let ephemeral_public = PublicKey::from(<[u8; KEY_SIZE]>::try_from(ephemeral_public).unwrap());
+                    self.identities.iter().find_map(|identity| {
+                        let shared = identity.diffie_hellman(&ephemeral_public);
+                        let key = recipient_key(shared.as_bytes(), &ephemeral_public, &PublicKey::from(identity));
+                        unwrap(&key, name, wrapped)
+                    })
+                }
+                _ => None,
+            };
+            if let Some(file_key) = unwrapped {
+                return Ok(file_key);
+            }
+        }
+        Err(format!("{name} can't be decrypted with the given key").into())
+    }
+}
+
+/// Key wrapping a file key for `recipient`, derived from the secret it shares with the
+/// ephemeral key of the object.
+fn recipient_key(shared: &[u8; KEY_SIZE], ephemeral_public: &PublicKey, recipient: &PublicKey) -> [u8; KEY_SIZE] {
+    let salt = [ephemeral_public.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"aws-backup x25519", &mut key)
+        .expect("key size is a valid HKDF output length");
+    key
+}
+
+/// Derives a key from `passphrase`, deliberately slowly to make guessing it expensive.
+pub fn derive_key(passphrase: &str, salt: &[u8; SALT_SIZE]) -> Result<[u8; KEY_SIZE], BoxError> {
+    let mut key = [0; KEY_SIZE];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("can't derive a key from the passphrase: {e}"))?;
+    Ok(key)
+}
+
+/// Encrypts `file_key` with `key`, bound to the object `name`.
+pub fn wrap(key: &[u8; KEY_SIZE], name: &str, file_key: &[u8; KEY_SIZE]) -> Result<Vec<u8>, BoxError> {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let wrapped = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: file_key, aad: name.as_bytes() })
+        .map_err(|_| "can't wrap the file key")?;
+    Ok([nonce.as_slice(), &wrapped].concat())
+}
+
+pub fn unwrap(key: &[u8; KEY_SIZE], name: &str, wrapped: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    if wrapped.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, wrapped) = wrapped.split_at(NONCE_SIZE);
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: wrapped, aad: name.as_bytes() })
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+/// Nonce of segment `index`; the last segment of an object gets a different one so a
+/// truncated object can't pass for a complete one.
+fn segment_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+pub fn random_key() -> [u8; KEY_SIZE] {
+    let mut key = [0; KEY_SIZE];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+pub fn random_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Writes the encryption of everything written to it into `inner`. `finish` has to be called
+/// to write the last segment.
+///
+/// An encrypted object starts with a header holding a random per-object file key wrapped with
+/// the user's key, followed by the data in segments of `SEGMENT_SIZE` bytes that are each
+/// sealed with ChaCha20-Poly1305 under the file key. Segments are numbered in their nonce and
+/// the last one is flagged, so reordering, truncating or extending the data is detected, and
+/// any segment can be decrypted on its own, which keeps ranged reads of archives possible.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    buffer: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, keys: &Keys, name: &str) -> Result<Self, BoxError> {
+        let file_key = random_key();
+        inner.write_all(&keys.header(name, &file_key)?)?;
+        Ok(EncryptWriter {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&file_key)),
+            buffer: Vec::with_capacity(SEGMENT_SIZE + TAG_SIZE),
+            index: 0,
+        })
+    }
+
+    fn write_segment(&mut self, last: bool) -> io::Result<()> {
+        let rest = self.buffer.split_off(self.buffer.len().min(SEGMENT_SIZE));
+        self.cipher
+            .encrypt_in_place(&segment_nonce(self.index, last), b"", &mut self.buffer)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "can't encrypt segment"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.buffer.extend(rest);
+        self.index += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.buffer.len() > SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+        self.write_segment(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full segment is only written once more data follows it, since the final segment
+        // has to be flagged as such.
+        if self.buffer.len() > SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+        let len = buf.len().min(SEGMENT_SIZE + 1 - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read and seek access to the plaintext of an encrypted object, decrypting one segment at a
+/// time.
+pub struct DecryptReader<R: Read + Seek> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    header_size: u64,
+    segments: u64,
+    size: u64,
+    position: u64,
+    segment: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> DecryptReader<R> {
+    pub fn new(mut inner: R, keys: &Keys, name: &str) -> Result<Self, BoxError> {
+        inner.seek(SeekFrom::Start(0))?;
+        let (header_size, stanzas) = read_header(&mut inner, name)?;
+        let file_key = keys.file_key(name, &stanzas)?;
+
+        let body = inner.seek(SeekFrom::End(0))? - header_size;
+        let stored_segment = (SEGMENT_SIZE + TAG_SIZE) as u64;
+        let segments = ((body + stored_segment - 1) / stored_segment).max(1);
+        let last = body - (segments - 1) * stored_segment;
+        if last < TAG_SIZE as u64 {
+            return Err(format!("{name} is truncated").into());
+        }
+        Ok(DecryptReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&file_key)),
+            header_size,
+            segments,
+            size: body - segments * TAG_SIZE as u64,
+            position: 0,
+            segment: None,
+        })
+    }
+
+    fn load_segment(&mut self, index: u64) -> io::Result<()> {
+        if matches!(&self.segment, Some((loaded, _)) if *loaded == index) {
+            return Ok(());
+        }
+        let stored_segment = (SEGMENT_SIZE + TAG_SIZE) as u64;
+        self.inner.seek(SeekFrom::Start(self.header_size + index * stored_segment))?;
+        let mut data = Vec::with_capacity(stored_segment as usize);
+        (&mut self.inner).take(stored_segment).read_to_end(&mut data)?;
+        self.cipher
+            .decrypt_in_place(&segment_nonce(index, index + 1 == self.segments), b"", &mut data)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "encrypted data has been tampered with"))?;
+        self.segment = Some((index, data));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / SEGMENT_SIZE as u64;
+        self.load_segment(index)?;
+        let data = &self.segment.as_ref().unwrap().1;
+        let offset = (self.position % SEGMENT_SIZE as u64) as usize;
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+/// Reads the header at the start of `inner`, returning its size and stanzas.
+fn read_header<R: Read>(inner: &mut R, name: &str) -> Result<(u64, Vec<Stanza>), BoxError> {
+    let mut start = [0; MAGIC.len() + 2];
+    inner.read_exact(&mut start).map_err(|_| format!("{name} is not encrypted"))?;
+    if &start[..MAGIC.len()] != MAGIC {
+        return Err(format!("{name} is not encrypted").into());
+    }
+    if start[MAGIC.len()] != VERSION {
+        return Err(format!("{name} is encrypted with an unsupported format version").into());
+    }
+
+    let mut size = start.len() as u64;
+    let mut stanzas = Vec::new();
+    for _ in 0..start[MAGIC.len() + 1] {
+        let mut stanza = [0; 3];
+        inner.read_exact(&mut stanza)?;
+        let mut body = vec![0; u16::from_be_bytes([stanza[1], stanza[2]]) as usize];
+        inner.read_exact(&mut body)?;
+        size += (stanza.len() + body.len()) as u64;
+        stanzas.push(Stanza { kind: stanza[0], body });
+    }
+    Ok((size, stanzas))
+}
+
+/// Checks that `data` stored as `name` is encrypted with `keys`, as far as they allow: keys
+/// that can only encrypt to recipients just check that it is encrypted at all.
+pub fn check(keys: Option<&Keys>, name: &str, data: Vec<u8>) -> Result<(), BoxError> {
+    match keys {
+        Some(keys) if !keys.can_decrypt() && !is_encrypted(&data) => Err(format!("{name} is not encrypted").into()),
+        Some(keys) if !keys.can_decrypt() => Ok(()),
+        _ => open(keys, name, data).map(|_| ()),
+    }
+}
+
+/// Checks that the file `path` to be stored as `name` is encrypted with `keys` like `check`,
+/// without reading more than its header.
+pub fn check_file(keys: Option<&Keys>, name: &str, path: &Path) -> Result<(), BoxError> {
+    let file = File::open(path)?;
+    match keys {
+        Some(keys) if !keys.can_decrypt() => {
+            let mut prefix = Vec::new();
+            file.take(MAGIC.len() as u64).read_to_end(&mut prefix)?;
+            match is_encrypted(&prefix) {
+                true => Ok(()),
+                false => Err(format!("{name} is not encrypted").into()),
+            }
+        }
+        _ => ObjectReader::new(file, keys, name).map(|_| ()),
+    }
+}
+
+/// Whether the object starting with `prefix` is encrypted.
+pub fn is_encrypted(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+/// Encrypts `data` for storing as `name` if `keys` are given.
+pub fn seal(keys: Option<&Keys>, name: &str, data: Vec<u8>) -> Result<Vec<u8>, BoxError> {
+    let Some(keys) = keys else { return Ok(data) };
+    let mut writer = EncryptWriter::new(Vec::with_capacity(data.len() + 1024), keys, name)?;
+    writer.write_all(&data)?;
+    Ok(writer.finish()?)
+}
+
+/// Decrypts `data` stored as `name`. Encrypted data is rejected without `keys` and plain data
+/// with them, so an encrypted object can't be replaced with a forged plain one.
+pub fn open(keys: Option<&Keys>, name: &str, data: Vec<u8>) -> Result<Vec<u8>, BoxError> {
+    match keys {
+        Some(keys) => {
+            let mut plain = Vec::new();
+            DecryptReader::new(io::Cursor::new(data), keys, name)?.read_to_end(&mut plain)?;
+            Ok(plain)
+        }
+        None if is_encrypted(&data) => Err(not_decryptable(name)),
+        None => Ok(data),
+    }
+}
+
+/// Encrypts the file `src` into `dst`, to be stored as `name`.
+pub fn seal_file(keys: &Keys, name: &str, src: &Path, dst: &Path) -> Result<(), BoxError> {
+    let mut writer = EncryptWriter::new(io::BufWriter::new(File::create(dst)?), keys, name)?;
+    io::copy(&mut File::open(src)?, &mut writer)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Either the plaintext of an encrypted object or a plain one, following the same rules as
+/// `open`.
+pub enum ObjectReader<R: Read + Seek> {
+    Plain(R),
+    Encrypted(DecryptReader<R>),
+}
+
+impl<R: Read + Seek> ObjectReader<R> {
+    pub fn new(mut inner: R, keys: Option<&Keys>, name: &str) -> Result<Self, BoxError> {
+        match keys {
+            Some(keys) => Ok(ObjectReader::Encrypted(DecryptReader::new(inner, keys, name)?)),
+            None => {
+                let mut prefix = [0; MAGIC.len()];
+                let len = inner.read(&mut prefix)?;
+                if is_encrypted(&prefix[..len]) {
+                    return Err(not_decryptable(name));
+                }
+                inner.seek(SeekFrom::Start(0))?;
+                Ok(ObjectReader::Plain(inner))
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for ObjectReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ObjectReader::Plain(inner) => inner.read(buf),
+            ObjectReader::Encrypted(inner) => inner.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for ObjectReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            ObjectReader::Plain(inner) => inner.seek(pos),
+            ObjectReader::Encrypted(inner) => inner.seek(pos),
+        }
+    }
+}
+
+fn not_decryptable(name: &str) -> BoxError {
+    format!("{name} is encrypted; pass --passphrase, --key-file or --identity to decrypt it").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::storage::StorageParams;
+
+    fn keys() -> Keys {
+        Keys {
+            passphrase: None,
+            key: Some(random_key()),
+            recipients: Vec::new(),
+            identities: Vec::new(),
+            salt: random_salt(),
+            derived: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn encrypt(keys: &Keys, data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), keys, "object").unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(keys: &Keys, encrypted: Vec<u8>) -> Result<Vec<u8>, BoxError> {
+        let mut reader = DecryptReader::new(Cursor::new(encrypted), keys, "object")?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_at_segment_boundaries() {
+        let keys = keys();
+        for len in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 2 * SEGMENT_SIZE] {
+            let data = data(len);
+            assert_eq!(decrypt(&keys, encrypt(&keys, &data)).unwrap(), data, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn seeks_across_segments() {
+        let keys = keys();
+        let data = data(2 * SEGMENT_SIZE + 100);
+        let mut reader = DecryptReader::new(Cursor::new(encrypt(&keys, &data)), &keys, "object").unwrap();
+        let mut buffer = vec![0; 200];
+        reader.seek(SeekFrom::Start(SEGMENT_SIZE as u64 - 100)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, data[SEGMENT_SIZE - 100..SEGMENT_SIZE + 100]);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn rejects_truncation() {
+        let keys = keys();
+        for len in [0, SEGMENT_SIZE, 2 * SEGMENT_SIZE] {
+            let encrypted = encrypt(&keys, &data(len));
+            // Dropping whole segments leaves a valid looking object whose new last segment
+            // wasn't sealed as the last one.
+            for cut in [1, SEGMENT_SIZE + TAG_SIZE] {
+                let Some(end) = encrypted.len().checked_sub(cut) else { continue };
+                assert!(decrypt(&keys, encrypted[..end].to_vec()).is_err(), "{len} bytes cut by {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let keys = keys();
+        let encrypted = encrypt(&keys, &data(2 * SEGMENT_SIZE));
+        for position in [encrypted.len() - 2 * (SEGMENT_SIZE + TAG_SIZE), encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[position] ^= 1;
+            assert!(decrypt(&keys, tampered).is_err(), "byte {position} flipped");
+        }
+    }
+
+    #[test]
+    fn rejects_other_keys_and_names() {
+        let (keys, other) = (keys(), keys());
+        let encrypted = encrypt(&keys, &data(10));
+        assert!(decrypt(&other, encrypted.clone()).is_err());
+        assert!(DecryptReader::new(Cursor::new(encrypted), &keys, "other").is_err());
+    }
+
+    #[tokio::test]
+    async fn uses_passphrases_unlocking_no_key_only_for_decrypting() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = StorageParams::try_parse_from(["test", "-b", dir.path().to_str().unwrap(), "--storage", "local"]);
+        let storage = storage.unwrap().open().await.unwrap();
+        crate::keyring::add(&storage, None, "one", None).await.unwrap();
+        let (_, master_key) = unlock(&storage, "one").await.unwrap().unwrap();
+        let params = |passphrase| EncryptionParams::try_parse_from(["test", "--passphrase", passphrase]).unwrap();
+
+        assert_eq!(params("one").keys(&storage, &[], false).await.unwrap().unwrap().key, Some(master_key));
+        assert!(params("wrong").keys(&storage, &[], false).await.is_err());
+        assert_eq!(params("wrong").keys(&storage, &[], true).await.unwrap().unwrap().key, None);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::runtime::Handle;
+
+use crate::BoxError;
+use crate::retry::{run_all, with_retries, Request, RETRY_DELAY};
+use crate::storage::{not_found, Storage};
+
+/// Smallest range `RangedReader` fetches; misses are aligned to it.
+const BLOCK_SIZE: u64 = 256 * 1024;
+/// Largest range `RangedReader` fetches once reads turn out to be sequential.
+const MAX_READ_AHEAD: u64 = 16 * 1024 * 1024;
+/// Number of fetched ranges `RangedReader` keeps around.
+const CACHED_RANGES: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DownloadConfig {
+    pub chunk_size: u64,
+    pub concurrency: usize,
+    pub retries: u32,
+}
+
+/// Which chunks of an object have already been written to the local file. The ETag pins the
+/// object version, so a resumed download never mixes chunks of two different uploads.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    pub e_tag: Option<String>,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: BTreeSet<u64>,
+}
+
+impl DownloadProgress {
+    fn is_resumable_as(&self, other: &DownloadProgress) -> bool {
+        self.e_tag == other.e_tag && self.size == other.size && self.chunk_size == other.chunk_size
+    }
+}
+
+/// Downloads `key` into `file_name` as `config.chunk_size` byte ranges, fetching up to
+/// `config.concurrency` ranges at once and retrying each independently. Chunks already
+/// recorded in `progress` are skipped if it still describes the same object; `checkpoint`
+/// is called after each chunk is written.
+pub async fn download_ranges<F>(
+    storage: &Arc<dyn Storage>,
+    key: &str,
+    file_name: &Path,
+    config: DownloadConfig,
+    progress: &mut Option<DownloadProgress>,
+    mut checkpoint: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&DownloadProgress) -> Result<(), BoxError>,
+{
+    let (size, e_tag) = storage.object_info(key).await?.ok_or_else(|| not_found(key))?;
+    let fresh = DownloadProgress {
+        e_tag,
+        size,
+        chunk_size: config.chunk_size.max(1),
+        chunks: BTreeSet::new(),
+    };
+    let progress = match progress {
+        Some(progress) if progress.is_resumable_as(&fresh) && file_name.is_file() => progress,
+        _ => {
+            let file = tokio::fs::File::create(file_name).await?;
+            file.set_len(size).await?;
+            progress.insert(fresh)
+        }
+    };
+    checkpoint(progress)?;
+
+    let chunk_count = (size + progress.chunk_size - 1) / progress.chunk_size;
+    let chunks: Vec<_> = (0..chunk_count)
+        .filter(|index| !progress.chunks.contains(index))
+        .map(|index| {
+            let offset = index * progress.chunk_size;
+            Chunk {
+                storage: storage.clone(),
+                key: key.to_string(),
+                e_tag: progress.e_tag.clone(),
+                file_name: file_name.to_path_buf(),
+                index,
+                offset,
+                length: progress.chunk_size.min(size - offset),
+            }
+        })
+        .collect();
+    run_all(chunks, config.concurrency, config.retries, |index| {
+        progress.chunks.insert(index);
+        checkpoint(progress)
+    })
+    .await
+}
+
+struct Chunk {
+    storage: Arc<dyn Storage>,
+    key: String,
+    e_tag: Option<String>,
+    file_name: PathBuf,
+    index: u64,
+    offset: u64,
+    length: u64,
+}
+
+#[async_trait]
+impl Request for Chunk {
+    type Output = u64;
+
+    async fn run(&self) -> Result<u64, BoxError> {
+        let mut body = self.storage
+            .get_range(&self.key, self.offset, self.offset + self.length, self.e_tag.as_deref())
+            .await?;
+
+        let mut file = OpenOptions::new().write(true).open(&self.file_name).await?;
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        let written = tokio::io::copy(&mut body, &mut file).await?;
+        if written != self.length {
+            return Err(format!("expected {} bytes at offset {}, got {written}", self.length, self.offset).into());
+        }
+        file.sync_data().await?;
+        Ok(self.index)
+    }
+}
+
+/// A `Read + Seek` view of an object that only fetches the ranges actually read, so a zip
+/// archive's central directory and a single entry can be read without downloading the rest.
+/// Sequential reads fetch progressively larger ranges. Blocks on `handle`, so it must be used
+/// from a blocking thread such as one started with `spawn_blocking`.
+pub struct RangedReader {
+    handle: Handle,
+    storage: Arc<dyn Storage>,
+    key: String,
+    e_tag: Option<String>,
+    retries: u32,
+    size: u64,
+    position: u64,
+    read_ahead: u64,
+    last_end: u64,
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl RangedReader {
+    pub fn new(
+        handle: Handle,
+        storage: Arc<dyn Storage>,
+        key: &str,
+        size: u64,
+        e_tag: Option<String>,
+        retries: u32,
+    ) -> Self {
+        RangedReader {
+            handle,
+            storage,
+            key: key.to_string(),
+            e_tag,
+            retries,
+            size,
+            position: 0,
+            read_ahead: BLOCK_SIZE,
+            last_end: u64::MAX,
+            cache: VecDeque::new(),
+        }
+    }
+
+    fn cached(&self) -> Option<&[u8]> {
+        self.cache
+            .iter()
+            .find(|(start, data)| (*start..*start + data.len() as u64).contains(&self.position))
+            .map(|(start, data)| &data[(self.position - start) as usize..])
+    }
+
+    fn fetch(&mut self) -> io::Result<()> {
+        let start = if self.position == self.last_end {
+            self.read_ahead = (self.read_ahead * 2).min(MAX_READ_AHEAD);
+            self.position
+        } else {
+            self.read_ahead = BLOCK_SIZE;
+            self.position - self.position % BLOCK_SIZE
+        };
+        let end = (start + self.read_ahead).min(self.size);
+
+        let this = &*self;
+        let request = move || async move {
+            let mut body = this.storage.get_range(&this.key, start, end, this.e_tag.as_deref()).await?;
+            let mut data = Vec::with_capacity((end - start) as usize);
+            body.read_to_end(&mut data).await?;
+            Ok::<_, BoxError>(data)
+        };
+        let data = self.handle
+            .block_on(with_retries(self.retries, RETRY_DELAY, request))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if data.len() as u64 != end - start {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short ranged read"));
+        }
+
+        if self.cache.len() == CACHED_RANGES {
+            self.cache.pop_front();
+        }
+        self.cache.push_back((start, data));
+        self.last_end = end;
+        Ok(())
+    }
+}
+
+impl Read for RangedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.cached().is_none() {
+            self.fetch()?;
+        }
+        let data = self.cached().unwrap_or_default();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RangedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of object"))?;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::Parser;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use walkdir::WalkDir;
+
+use crate::BoxError;
+
+/// Name of the files listing what to leave out of the directory they are in and below it,
+/// in gitignore syntax.
+pub const IGNORE_FILE: &str = ".backupignore";
+
+/// Name of the file tagging a directory as a cache, per https://bford.info/cachedir/.
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// What a valid CACHEDIR.TAG starts with.
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Patterns picking out what a backup leaves out, in gitignore syntax and relative to the
+/// backed up path.
+#[derive(Parser, Debug)]
+pub struct FilterParams {
+    #[arg(long = "exclude", help = "leave out paths matching this gitignore style pattern; may be repeated")]
+    pub exclude: Vec<String>,
+    #[arg(long = "include", help = "back up paths matching this pattern even if excluded, unless a directory above them is; may be repeated")]
+    pub include: Vec<String>,
+    #[arg(long = "exclude-from", help = "leave out paths matching the patterns in this file, one per line in gitignore syntax; may be repeated")]
+    pub exclude_from: Vec<PathBuf>,
+    #[arg(long = "exclude-caches", help = "leave out directories tagged as caches by a valid CACHEDIR.TAG, unless a pattern includes them")]
+    pub exclude_caches: bool,
+}
+
+impl FilterParams {
+    /// Compiles the patterns for a backup of `src`.
+    pub fn filter(&self, src: &str) -> Result<Filter, BoxError> {
+        let mut builder = GitignoreBuilder::new(src);
+        for file in &self.exclude_from {
+            if let Some(e) = builder.add(file) {
+                return Err(format!("can't read patterns: {e}").into());
+            }
+        }
+        for pattern in &self.exclude {
+            builder.add_line(None, pattern)?;
+        }
+        for pattern in &self.include {
+            builder.add_line(None, &format!("!{pattern}"))?;
+        }
+        Ok(Filter {
+            patterns: builder.build()?,
+            exclude_caches: self.exclude_caches,
+            skipped_caches: AtomicU64::new(0),
+            skipped_bytes: AtomicU64::new(0),
+        })
+    }
+}
+
+/// Decides which entries of a tree are left out: the command line patterns come first, later
+/// ones winning over earlier ones and includes over excludes, then those of the `.backupignore`
+/// files of the directories an entry is in, the innermost first. Cache directories are left
+/// out after that, if asked to, and counted.
+pub struct Filter {
+    patterns: Gitignore,
+    exclude_caches: bool,
+    skipped_caches: AtomicU64,
+    skipped_bytes: AtomicU64,
+}
+
+impl Filter {
+    /// Whether the entry at `path` is left out, given the ignore files of the directories
+    /// it's in, as loaded by `ignore_file`.
+    pub fn excludes(&self, path: &Path, is_dir: bool, ignore_files: &[(usize, Gitignore)]) -> bool {
+        let matches = std::iter::once(&self.patterns)
+            .chain(ignore_files.iter().rev().map(|(_, ignore)| ignore))
+            .map(|ignore| ignore.matched(path, is_dir));
+        for matched in matches {
+            match matched {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        if is_dir && self.exclude_caches && is_cache_dir(path) {
+            self.skipped_caches.fetch_add(1, Ordering::Relaxed);
+            self.skipped_bytes.fetch_add(tree_size(path), Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Number of cache directories left out so far, and bytes of files in them.
+    pub fn skipped_caches(&self) -> (u64, u64) {
+        (self.skipped_caches.load(Ordering::Relaxed), self.skipped_bytes.load(Ordering::Relaxed))
+    }
+}
+
+/// Whether `dir` holds a CACHEDIR.TAG file, not a link, starting with the signature.
+fn is_cache_dir(dir: &Path) -> bool {
+    let path = dir.join(CACHEDIR_TAG);
+    if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+        return false;
+    }
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    let read = File::open(&path).and_then(|mut file| file.read_exact(&mut signature));
+    read.is_ok() && signature == CACHEDIR_SIGNATURE
+}
+
+/// Total size of the files below `dir`, not following links and leaving out anything that
+/// can't be read.
+fn tree_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// The patterns of the `.backupignore` file in the directory `dir`, if it has one. Lines that
+/// aren't valid patterns are skipped with a warning.
+pub fn ignore_file(dir: &Path) -> Option<Gitignore> {
+    let path = dir.join(IGNORE_FILE);
+    if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+        return None;
+    }
+    let (ignore, error) = Gitignore::new(&path);
+    if let Some(e) = error {
+        eprintln!("skipping patterns in {}: {e}", path.display());
+    }
+    Some(ignore)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::archive::{walk_tree, Symlinks, WalkOptions};
+
+    /// A temporary tree holding `files`, each with its contents.
+    fn tree(files: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    /// Names of the entries of `dir` a backup with the filter options `args` keeps, and the
+    /// filter it used.
+    fn walk(dir: &TempDir, args: &[&str]) -> (Vec<String>, Filter) {
+        let src = dir.path().to_str().unwrap();
+        let filter = FilterParams::try_parse_from(["test"].iter().chain(args)).unwrap().filter(src).unwrap();
+        let options = WalkOptions { symlinks: Symlinks::Store, filter };
+        let mut names = Vec::new();
+        walk_tree(src, &options, |name, _, _| {
+            names.push(name.to_string());
+            Ok(())
+        })
+        .unwrap();
+        names.sort();
+        (names, options.filter)
+    }
+
+    #[test]
+    fn later_patterns_and_includes_win() {
+        let dir = tree(&[("a.log", ""), ("keep.log", ""), ("b.txt", ""), ("build/keep.txt", ""), ("build/x.o", "")]);
+        let (names, _) = walk(&dir, &["--exclude", "*.log", "--include", "keep.log", "--exclude", "build/", "--include", "build/keep.txt"]);
+        assert_eq!(names, ["b.txt", "keep.log"]);
+    }
+
+    #[test]
+    fn reads_patterns_from_files() {
+        let dir = tree(&[("a.log", ""), ("b.txt", ""), ("dir/c.log", "")]);
+        let patterns = tempfile::NamedTempFile::new().unwrap();
+        fs::write(patterns.path(), "# logs\n*.log\n").unwrap();
+        let (names, _) = walk(&dir, &["--exclude-from", patterns.path().to_str().unwrap()]);
+        assert_eq!(names, ["b.txt", "dir"]);
+        assert!(FilterParams::try_parse_from(["test", "--exclude-from", "/nonexistent"]).unwrap().filter("/").is_err());
+    }
+
+    #[test]
+    fn applies_nested_ignore_files_innermost_first() {
+        let dir = tree(&[
+            (IGNORE_FILE, "*.tmp\n"),
+            ("a.tmp", ""),
+            ("a.txt", ""),
+            ("sub/.backupignore", "!keep.tmp\n*.txt\n"),
+            ("sub/keep.tmp", ""),
+            ("sub/x.tmp", ""),
+            ("sub/y.txt", ""),
+            ("other/z.txt", ""),
+        ]);
+        let (names, _) = walk(&dir, &[]);
+        assert_eq!(names, [IGNORE_FILE, "a.txt", "other", "other/z.txt", "sub", "sub/.backupignore", "sub/keep.tmp"]);
+
+        // Command line patterns come ahead of ignore files.
+        let (names, _) = walk(&dir, &["--include", "sub/x.tmp", "--exclude", "other"]);
+        assert_eq!(names, [IGNORE_FILE, "a.txt", "sub", "sub/.backupignore", "sub/keep.tmp", "sub/x.tmp"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_directories_with_a_valid_cachedir_tag() {
+        let tag = format!("{}\n# a cache\n", std::str::from_utf8(CACHEDIR_SIGNATURE).unwrap());
+        let dir = tree(&[
+            ("a.txt", ""),
+            ("cache/CACHEDIR.TAG", &tag),
+            ("cache/data", "12345"),
+            ("forged/CACHEDIR.TAG", "Signature: not the right one"),
+            ("forged/data", ""),
+        ]);
+        // A tag has to be a file of its own, not a link to another directory's.
+        fs::create_dir(dir.path().join("linked")).unwrap();
+        std::os::unix::fs::symlink("../cache/CACHEDIR.TAG", dir.path().join("linked/CACHEDIR.TAG")).unwrap();
+
+        let (names, filter) = walk(&dir, &[]);
+        assert!(names.contains(&"cache/data".to_string()));
+        assert_eq!(filter.skipped_caches(), (0, 0));
+
+        let (names, filter) = walk(&dir, &["--exclude-caches"]);
+        assert_eq!(names, ["a.txt", "forged", "forged/CACHEDIR.TAG", "forged/data", "linked", "linked/CACHEDIR.TAG"]);
+        assert_eq!(filter.skipped_caches(), (1, tag.len() as u64 + 5));
+
+        let (names, _) = walk(&dir, &["--exclude-caches", "--include", "cache/"]);
+        assert!(names.contains(&"cache/data".to_string()));
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::BoxError;
+use crate::crypto::{derive_key, random_key, random_salt, unwrap, wrap, KEY_SIZE, SALT_SIZE};
+use crate::storage::Storage;
+
+/// Prefix the bucket's keys are stored under. Each one holds the bucket's master key wrapped
+/// with a key derived from a different passphrase, so passphrases can be added, changed and
+/// removed without re-encrypting any backups.
+pub const KEY_PREFIX: &str = "keys/";
+
+#[derive(Serialize, Deserialize)]
+struct KeyObject {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// Seconds since the Unix epoch.
+    created: u64,
+    salt: String,
+    master_key: String,
+}
+
+/// A key as shown by `key list`.
+pub struct KeyInfo {
+    pub id: String,
+    pub label: Option<String>,
+    pub created: u64,
+}
+
+fn key_object(id: &str) -> String {
+    format!("{KEY_PREFIX}{id}.json")
+}
+
+async fn load_keys(storage: &Arc<dyn Storage>) -> Result<Vec<(String, KeyObject)>, BoxError> {
+    let mut keys = Vec::new();
+    for object in storage.list(KEY_PREFIX).await? {
+        let Some(id) = object.strip_prefix(KEY_PREFIX).and_then(|name| name.strip_suffix(".json")) else {
+            continue;
+        };
+        keys.push((id.to_string(), serde_json::from_slice(&storage.get(&object).await?)?));
+    }
+    Ok(keys)
+}
+
+/// Unlocks the bucket's master key with `passphrase`, returning the id of the key that did
+/// it along with the master key, or `None` if none of the bucket's keys, if any, is unlocked
+/// by it.
+pub async fn unlock(storage: &Arc<dyn Storage>, passphrase: &str) -> Result<Option<(String, [u8; KEY_SIZE])>, BoxError> {
+    for (id, key) in load_keys(storage).await? {
+        let salt: [u8; SALT_SIZE] = hex::decode(&key.salt)?
+            .try_into()
+            .map_err(|_| format!("key {id} is corrupted"))?;
+        let wrapping_key = derive_key(passphrase, &salt)?;
+        if let Some(master_key) = unwrap(&wrapping_key, &key_object(&id), &hex::decode(&key.master_key)?) {
+            return Ok(Some((id, master_key)));
+        }
+    }
+    Ok(None)
+}
+
+/// Like `unlock`, but failing if the passphrase unlocks none of the bucket's keys.
+async fn unlock_any(storage: &Arc<dyn Storage>, passphrase: &str) -> Result<(String, [u8; KEY_SIZE]), BoxError> {
+    unlock(storage, passphrase).await?.ok_or_else(|| "the passphrase doesn't unlock any of the bucket's keys".into())
+}
+
+async fn store_key(
+    storage: &Arc<dyn Storage>,
+    id: &str,
+    label: Option<String>,
+    created: u64,
+    passphrase: &str,
+    master_key: &[u8; KEY_SIZE],
+) -> Result<(), BoxError> {
+    let salt = random_salt();
+    let wrapped = wrap(&derive_key(passphrase, &salt)?, &key_object(id), master_key)?;
+    let key = KeyObject { label, created, salt: hex::encode(salt), master_key: hex::encode(wrapped) };
+    storage.put(&key_object(id), serde_json::to_vec_pretty(&key)?).await
+}
+
+/// Adds a key unlocked by `new_passphrase` and returns its id. The first key creates the
+/// master key; any later one needs `passphrase` to unlock the existing master key.
+pub async fn add(
+    storage: &Arc<dyn Storage>,
+    passphrase: Option<&str>,
+    new_passphrase: &str,
+    label: Option<String>,
+) -> Result<String, BoxError> {
+    let first = load_keys(storage).await?.is_empty();
+    let master_key = match passphrase {
+        _ if first => random_key(),
+        Some(passphrase) => unlock_any(storage, passphrase).await?.1,
+        None => return Err("the bucket already has keys; pass --passphrase to unlock one of them".into()),
+    };
+    let id = Uuid::new_v4().simple().to_string();
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    store_key(storage, &id, label, created, new_passphrase, &master_key).await?;
+    Ok(id)
+}
+
+pub async fn list(storage: &Arc<dyn Storage>) -> Result<Vec<KeyInfo>, BoxError> {
+    Ok(load_keys(storage).await?
+        .into_iter()
+        .map(|(id, key)| KeyInfo { id, label: key.label, created: key.created })
+        .collect())
+}
+
+/// Removes the key `id`. `passphrase` has to unlock one of the other keys, so access to the
+/// backups can't be lost by removing the last key that works.
+pub async fn remove(storage: &Arc<dyn Storage>, passphrase: &str, id: &str) -> Result<(), BoxError> {
+    if !load_keys(storage).await?.iter().any(|(key_id, _)| key_id == id) {
+        return Err(format!("the bucket has no key {id}").into());
+    }
+    match unlock_any(storage, passphrase).await? {
+        (unlocked, _) if unlocked != id => storage.delete(&key_object(id)).await,
+        _ => Err(format!("the passphrase has to unlock a key other than {id}").into()),
+    }
+}
+
+/// Rewraps the master key for the key `passphrase` unlocks with `new_passphrase` and returns
+/// the key's id.
+pub async fn change_passphrase(
+    storage: &Arc<dyn Storage>,
+    passphrase: &str,
+    new_passphrase: &str,
+) -> Result<String, BoxError> {
+    let (id, master_key) = unlock_any(storage, passphrase).await?;
+    let (_, key) = load_keys(storage).await?.into_iter().find(|(key_id, _)| *key_id == id).unwrap();
+    store_key(storage, &id, key.label, key.created, new_passphrase, &master_key).await?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::StorageParams;
+
+    async fn storage(dir: &TempDir) -> Arc<dyn Storage> {
+        let params = StorageParams::try_parse_from(["test", "-b", dir.path().to_str().unwrap(), "--storage", "local"]);
+        params.unwrap().open().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn adds_keys_unlocking_the_same_master_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+        assert!(unlock(&storage, "one").await.unwrap().is_none());
+
+        let first = add(&storage, None, "one", None).await.unwrap();
+        let (id, master_key) = unlock(&storage, "one").await.unwrap().unwrap();
+        assert_eq!(id, first);
+        assert!(add(&storage, None, "two", None).await.is_err());
+        assert!(add(&storage, Some("wrong"), "two", None).await.is_err());
+
+        let second = add(&storage, Some("one"), "two", Some("laptop".to_string())).await.unwrap();
+        assert_eq!(unlock(&storage, "two").await.unwrap(), Some((second.clone(), master_key)));
+        assert!(unlock(&storage, "wrong").await.unwrap().is_none());
+        let labels: Vec<_> = list(&storage).await.unwrap().into_iter().map(|key| (key.id, key.label)).collect();
+        assert_eq!(labels.len(), 2);
+        assert!(labels.contains(&(first, None)) && labels.contains(&(second, Some("laptop".to_string()))));
+    }
+
+    #[tokio::test]
+    async fn removes_keys_and_changes_passphrases() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+        let first = add(&storage, None, "one", None).await.unwrap();
+        let second = add(&storage, Some("one"), "two", None).await.unwrap();
+        let (_, master_key) = unlock(&storage, "one").await.unwrap().unwrap();
+
+        assert!(remove(&storage, "one", &first).await.is_err());
+        assert!(remove(&storage, "two", "missing").await.is_err());
+        remove(&storage, "two", &first).await.unwrap();
+        assert!(unlock(&storage, "one").await.unwrap().is_none());
+        assert!(remove(&storage, "two", &second).await.is_err());
+
+        assert_eq!(change_passphrase(&storage, "two", "three").await.unwrap(), second);
+        assert!(unlock(&storage, "two").await.unwrap().is_none());
+        assert_eq!(unlock(&storage, "three").await.unwrap(), Some((second, master_key)));
+        assert_eq!(list(&storage).await.unwrap().len(), 1);
+    }
+}
@@ -1,23 +1,45 @@
-use std::str;
 use std::error::Error;
-use std::io::{Read, Write};
-use std::fs::File;
-use std::io::Seek;
-use std::path::Path;
-use tokio::io::copy;
-
-use aws_sdk_s3::{Client, Region};
-use aws_sdk_s3::error::{CreateBucketError, GetObjectError, PutObjectError};
-use aws_sdk_s3::output::{GetObjectOutput, PutObjectOutput};
-use aws_sdk_s3::types::{ByteStream, SdkError};
-
-use clap::Parser;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use clap::{Parser, Subcommand};
 use tempfile::NamedTempFile;
-use walkdir::{DirEntry, WalkDir};
+use tokio::runtime::Handle;
+
+use zip::ZipArchive;
+
+use crate::archive::{entry_path, extract_entry, set_metadata, zip_dir, ArchiveOptions, ArchiveSummary, Codec, Compression, Format, IncompressibleParams, Symlinks, WalkOptions};
+use crate::crypto::{check_file, generate_identity, seal, seal_file, EncryptionParams, Keys, ObjectReader};
+use crate::download::{download_ranges, DownloadConfig, RangedReader};
+use crate::filter::{Filter, FilterParams};
+use crate::manifest::{manifest_key, EntryKind, Manifest, ManifestBuilder, ManifestEntry};
+use crate::owner::{OwnerMap, OwnerParams};
+use crate::pax::{tar_dir, TarEntry, Unpacker};
+use crate::repository::{backup_to_repository, restore_from_repository};
+use crate::state::{manifest_cache, write_private, BackupState, RestoreState};
+use crate::storage::{not_found, BucketSettings, Storage, StorageParams, UploadConfig};
+
+mod archive;
+mod config;
+mod crypto;
+mod download;
+mod filter;
+mod keyring;
+mod manifest;
+mod owner;
+mod pax;
+mod repository;
+mod retry;
+mod sparse;
+mod state;
+mod storage;
 
-use zip::{ZipArchive, ZipWriter};
-use zip::result::ZipError;
-use zip::write::FileOptions;
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
 
 #[derive(Parser, Debug)]
 enum Args {
@@ -26,162 +48,1023 @@ enum Args {
 
     #[command(name = "restore", about = "restore files")]
     Restore(RestoreParams),
+
+    #[command(name = "keygen", about = "generate a key pair whose public key backups can be encrypted to")]
+    Keygen(KeygenParams),
+
+    #[command(name = "key", about = "manage the passphrases unlocking the bucket's master key", subcommand)]
+    Key(KeyArgs),
 }
 
 #[derive(Parser, Debug)]
 struct BackupParams {
     #[arg(short = 'p')]
     path: String,
-    #[arg(short = 'b')]
-    bucket: String,
+    #[command(flatten)]
+    storage: StorageParams,
     #[arg(short = 'k')]
     key: String,
+    #[command(flatten)]
+    bucket_settings: BucketSettings,
+    #[command(flatten)]
+    encryption: EncryptionParams,
+    #[arg(long = "recipient", help = "also encrypt to this public key, as printed by keygen; may be repeated")]
+    recipients: Vec<String>,
+    #[arg(long = "parent", help = "key of a previous backup; only files changed since then are uploaded")]
+    parent: Option<String>,
+    #[arg(long = "hash", help = "detect changed files by content hash instead of size, mtime and inode")]
+    hash: bool,
+    #[arg(long = "repository", help = "store files as deduplicated content-defined chunks instead of an archive")]
+    repository: bool,
+    #[arg(long = "symlinks", value_enum, default_value_t = Symlinks::Store, help = "how symlinks are backed up")]
+    symlinks: Symlinks,
+    #[command(flatten)]
+    filter: FilterParams,
+    #[arg(long = "format", value_enum, default_value_t = Format::Zip, conflicts_with = "repository", help = "how files are packed into the archive")]
+    format: Format,
+    #[arg(long = "compression", value_enum, default_value_t = Codec::Deflate, help = "how archive entries are compressed")]
+    compression: Codec,
+    #[arg(long = "compression-level", allow_negative_numbers = true, help = "codec specific compression level, the codec's default if not set")]
+    compression_level: Option<i32>,
+    #[command(flatten)]
+    incompressible: IncompressibleParams,
+    #[arg(long = "threads", default_value_t = default_threads(), help = "number of files compressed at once")]
+    threads: usize,
+    #[arg(long = "buffer-size", default_value_t = DEFAULT_BUFFER_SIZE, help = "maximum bytes of file data held in memory at once")]
+    buffer_size: usize,
+    #[arg(long = "part-size", default_value_t = 64 * 1024 * 1024, help = "size in bytes of each multipart upload part")]
+    part_size: u64,
+    #[arg(long = "concurrency", default_value_t = 4, help = "number of parts uploaded at once")]
+    concurrency: usize,
+    #[arg(long = "retries", default_value_t = 3, help = "number of times a failed part is retried")]
+    retries: u32,
+    #[arg(long = "state-dir", help = "directory holding archives and progress of unfinished backups")]
+    state_dir: Option<PathBuf>,
+    #[arg(long = "no-resume", help = "start from scratch and abort the upload on failure instead of keeping progress")]
+    no_resume: bool,
 }
 
 #[derive(Parser, Debug)]
 struct RestoreParams {
     #[arg(short = 'p')]
     path: String,
-    #[arg(short = 'b')]
-    bucket: String,
+    #[command(flatten)]
+    storage: StorageParams,
     #[arg(short = 'k')]
     key: String,
     #[arg(short = 'f')]
     file: Option<String>,
+    #[command(flatten)]
+    encryption: EncryptionParams,
+    #[arg(long = "chunk-size", default_value_t = 64 * 1024 * 1024, help = "size in bytes of each ranged download")]
+    chunk_size: u64,
+    #[arg(long = "concurrency", default_value_t = 4, help = "number of ranges downloaded at once")]
+    concurrency: usize,
+    #[arg(long = "retries", default_value_t = 3, help = "number of times a failed range is retried")]
+    retries: u32,
+    #[arg(long = "state-dir", help = "directory holding archives and progress of unfinished restores")]
+    state_dir: Option<PathBuf>,
+    #[arg(long = "no-resume", help = "start the download from scratch instead of keeping progress")]
+    no_resume: bool,
+    #[arg(long = "no-xattrs", help = "leave out extended attributes, ACLs and file capabilities, which only root can restore in full")]
+    no_xattrs: bool,
+    #[command(flatten)]
+    owners: OwnerParams,
+}
+
+#[derive(Parser, Debug)]
+struct KeygenParams {
+    #[arg(short = 'o', help = "file to write the private key to")]
+    output: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyArgs {
+    #[command(name = "add", about = "add a passphrase, creating the master key if the bucket has none")]
+    Add(KeyAddParams),
+
+    #[command(name = "list", about = "list the bucket's keys")]
+    List(KeyListParams),
+
+    #[command(name = "remove", about = "remove a key")]
+    Remove(KeyRemoveParams),
+
+    #[command(name = "change-passphrase", about = "change the passphrase of the key the current one unlocks")]
+    ChangePassphrase(KeyChangeParams),
+}
+
+#[derive(Parser, Debug)]
+struct KeyringParams {
+    #[command(flatten)]
+    storage: StorageParams,
+    #[arg(long = "passphrase", env = "AWS_BACKUP_PASSPHRASE", hide_env_values = true, help = "a passphrase unlocking one of the bucket's keys")]
+    passphrase: Option<String>,
+}
+
+impl KeyringParams {
+    fn required_passphrase(&self) -> Result<String, BoxError> {
+        self.passphrase.clone().ok_or_else(|| "--passphrase is required to unlock the bucket's keys".into())
+    }
+}
+
+#[derive(Parser, Debug)]
+struct KeyAddParams {
+    #[command(flatten)]
+    keyring: KeyringParams,
+    #[arg(long = "new-passphrase", env = "AWS_BACKUP_NEW_PASSPHRASE", hide_env_values = true, help = "passphrase of the new key")]
+    new_passphrase: String,
+    #[arg(long = "label", help = "description of the new key shown by key list")]
+    label: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct KeyListParams {
+    #[command(flatten)]
+    storage: StorageParams,
+}
+
+#[derive(Parser, Debug)]
+struct KeyRemoveParams {
+    #[command(flatten)]
+    keyring: KeyringParams,
+    #[arg(help = "id of the key to remove, as shown by key list")]
+    id: String,
+}
+
+#[derive(Parser, Debug)]
+struct KeyChangeParams {
+    #[command(flatten)]
+    keyring: KeyringParams,
+    #[arg(long = "new-passphrase", env = "AWS_BACKUP_NEW_PASSPHRASE", hide_env_values = true, help = "passphrase replacing the current one")]
+    new_passphrase: String,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    match Args::parse() {
+async fn main() -> Result<(), BoxError> {
+    run(Args::parse()).await
+}
+
+async fn run(args: Args) -> Result<(), BoxError> {
+    match args {
         Args::Backup(params) => backup(params).await,
         Args::Restore(params) => restore(params).await,
+        Args::Keygen(params) => keygen(params),
+        Args::Key(args) => key(args).await,
     }
 }
 
-async fn backup(BackupParams { path, bucket, key }: BackupParams) -> Result<(), Box<dyn Error>> {
-    let client = get_client().await;
-    create_bucket_if_not_exists(&client, &bucket).await?;
+async fn backup(params: BackupParams) -> Result<(), BoxError> {
+    let BackupParams {
+        path, storage, key, bucket_settings, encryption, recipients, parent, hash, repository, symlinks, filter,
+        format, compression, compression_level, incompressible, threads, buffer_size, part_size, concurrency,
+        retries, state_dir, no_resume,
+    } = params;
+    let upload_config = UploadConfig { part_size, concurrency, retries };
+    // Everything shaping the archive of a resumable backup, other than the keys, which are
+    // tried on the archive instead.
+    let options = format!(
+        "{format:?} {compression:?} {compression_level:?} {parent:?} {hash} {symlinks:?} {filter:?} {incompressible:?} {recipients:?}"
+    );
+    let walk = WalkOptions { symlinks, filter: filter.filter(&path)? };
+    let archive_options = ArchiveOptions {
+        format,
+        compression: Compression::new(format, compression, compression_level)?,
+        incompressible: &incompressible,
+        threads,
+        walk: &walk,
+        buffer_size,
+    };
+    let state_dir = match state_dir {
+        Some(state_dir) => state_dir,
+        None => default_state_dir()?,
+    };
+    let backend = storage.open().await?;
+    backend.create_if_not_exists(&bucket_settings).await?;
+    let keys = encryption.keys(&backend, &recipients, false).await?.map(Arc::new);
+
+    // Hosts that can only encrypt to recipients keep plain copies of their manifests so later
+    // backups can still be incremental.
+    let cache = keys
+        .as_ref()
+        .filter(|keys| !keys.can_decrypt())
+        .map(|_| (state_dir.as_path(), storage.bucket.as_str()));
+    let parent_manifest = match &parent {
+        Some(parent) if cache.is_some() => {
+            Some(load_cached_manifest(&backend, &state_dir, &storage.bucket, parent).await?)
+        }
+        Some(parent) => Some(Manifest::load(&backend, parent, keys.as_deref()).await?.ok_or_else(|| {
+            format!("{parent} has no manifest and can't be used as the parent of an incremental backup")
+        })?),
+        None => None,
+    };
+    if let (Some(parent), Some(parent_manifest)) = (&parent, &parent_manifest) {
+        // Unchanged files are restored from older archives as if they were of this snapshot's
+        // format, and with its codec for tar archives compressed as a whole.
+        let same_format = match repository {
+            true => parent_manifest.repository,
+            false => {
+                !parent_manifest.repository
+                    && parent_manifest.format == format
+                    && (format == Format::Zip
+                        || parent_manifest.compression.map(|compression| compression.codec) == Some(compression))
+            }
+        };
+        if !same_format {
+            return Err(format!("{parent} was not stored in the same format and can't be used as the parent").into());
+        }
+    }
+    let mut builder = ManifestBuilder::new(&key, parent.as_deref(), parent_manifest.as_ref(), hash, repository);
+
+    let manifest_key = manifest_key(&key);
 
-    let mut temp = NamedTempFile::new()?;
-    zip_dir(&path, &mut temp, zip::CompressionMethod::Deflated)?;
+    if repository {
+        let manifest = backup_to_repository(&backend, &path, builder, keys.clone(), &walk, concurrency, retries).await?;
+        backend.put(&manifest_key, store_manifest(&manifest, &key, keys.as_deref(), cache)?).await?;
+        print_skipped(&walk.filter);
+        return Ok(());
+    }
 
-    upload_object(&client, &bucket, temp.path(), &key).await?;
+    if no_resume {
+        let temp = NamedTempFile::new()?;
+        let summary = write_archive(&path, temp.path(), &key, keys.as_deref(), &archive_options, &mut builder)?;
+        let manifest = store_manifest(&builder.finish(), &key, keys.as_deref(), cache)?;
+        let mut progress = None;
+        let result = backend.upload_file(&key, temp.path(), upload_config, &mut progress, &mut |_| Ok(())).await;
+        if let (Err(_), Some(progress)) = (&result, &progress) {
+            let _ = backend.abort_upload(&key, progress).await;
+        }
+        result?;
+        backend.put(&manifest_key, manifest).await?;
+        print_summary(&summary);
+        print_skipped(&walk.filter);
+        return Ok(());
+    }
+
+    let mut state = BackupState::load_or_new(&state_dir, &path, &storage.bucket, &key, &options)?;
+    if state.archive_complete && check_file(keys.as_deref(), &key, &state.archive).is_err() {
+        state.restart();
+    }
+    let mut summary = None;
+    if !state.archive_complete {
+        summary = Some(write_archive(&path, &state.archive, &key, keys.as_deref(), &archive_options, &mut builder)?);
+        fs::write(&state.manifest, store_manifest(&builder.finish(), &key, keys.as_deref(), cache)?)?;
+        state.archive_complete = true;
+        state.save()?;
+    }
+    let archive = state.archive.clone();
+    let mut upload = state.upload.take();
+    backend.upload_file(&key, &archive, upload_config, &mut upload, &mut |progress| {
+        state.upload = Some(progress.clone());
+        Ok(state.save()?)
+    }).await?;
+    backend.put(&manifest_key, fs::read(&state.manifest)?).await?;
+    state.remove()?;
+    if let Some(summary) = summary {
+        print_summary(&summary);
+        print_skipped(&walk.filter);
+    }
 
     Ok(())
 }
 
-async fn restore(RestoreParams { path, bucket, key, file }: RestoreParams) -> Result<(), Box<dyn Error>> {
-    let client = get_client().await;
+fn print_summary(summary: &ArchiveSummary) {
+    println!(
+        "archived {} files, {} bytes; {} incompressible files, {} bytes, stored without compression",
+        summary.files, summary.bytes, summary.stored_files, summary.stored_bytes,
+    );
+}
 
-    let stream = download_object(&client, &bucket, &key).await?.body;
-    let temp = NamedTempFile::new()?;
-    let mut temp2 = temp.reopen()?;
-    let mut reader = stream.into_async_read();
-    let mut temp = tokio::fs::File::from_std(temp.into_file());
-    copy(&mut reader, &mut temp).await?;
+/// Reports the cache directories `--exclude-caches` left out, if any.
+fn print_skipped(filter: &Filter) {
+    let (dirs, bytes) = filter.skipped_caches();
+    if dirs > 0 {
+        println!("skipped {dirs} cache directories, {bytes} bytes");
+    }
+}
+
+/// Serializes the manifest of the snapshot `key` for storing, and keeps a plain copy of it in
+/// `cache`, a state directory and bucket, if given.
+fn store_manifest(
+    manifest: &Manifest,
+    key: &str,
+    keys: Option<&Keys>,
+    cache: Option<(&Path, &str)>,
+) -> Result<Vec<u8>, BoxError> {
+    let plain = serde_json::to_vec(manifest)?;
+    if let Some((state_dir, bucket)) = cache {
+        write_private(&manifest_cache(state_dir, bucket, key), &plain)?;
+    }
+    seal(keys, &manifest_key(key), plain)
+}
+
+/// Loads the plain copy of the manifest of `key` kept by a host that can't decrypt the one in
+/// the bucket, as long as the snapshot made it to the bucket.
+async fn load_cached_manifest(
+    backend: &Arc<dyn Storage>,
+    state_dir: &Path,
+    bucket: &str,
+    key: &str,
+) -> Result<Manifest, BoxError> {
+    let cached = manifest_cache(state_dir, bucket, key);
+    if !cached.is_file() || backend.object_info(&manifest_key(key)).await?.is_none() {
+        return Err(format!(
+            "{key} has no manifest cached on this host and can't be used as the parent of an incremental backup"
+        ).into());
+    }
+    Ok(serde_json::from_slice(&fs::read(cached)?)?)
+}
+
+/// Archives the files under `path` that `builder` considers changed into `dst`, recording
+/// every entry in its manifest. With `keys` the archive is encrypted for storing as `key`.
+fn write_archive(
+    path: &str,
+    dst: &Path,
+    key: &str,
+    keys: Option<&Keys>,
+    options: &ArchiveOptions,
+    builder: &mut ManifestBuilder,
+) -> Result<ArchiveSummary, BoxError> {
+    builder.set_format(options.format, options.compression);
+    let mut pack = |dst: &File| -> Result<ArchiveSummary, BoxError> {
+        Ok(match options.format {
+            Format::Zip => zip_dir(path, dst, options, |name, path, metadata| builder.select(name, path, metadata))?,
+            Format::Tar => tar_dir(path, BufWriter::new(dst), options, |name, path, metadata| {
+                Ok(match builder.select(name, path, metadata)? {
+                    true => Some(TarEntry::Data(builder.extents(name))),
+                    false => builder.stored_link(name).map(TarEntry::Link),
+                })
+            })?,
+        })
+    };
+    Ok(match keys {
+        None => pack(&File::create(dst)?)?,
+        Some(keys) => {
+            let plain = NamedTempFile::new()?;
+            let summary = pack(plain.as_file())?;
+            seal_file(keys, key, plain.path(), dst)?;
+            summary
+        }
+    })
+}
 
-    let mut archive = ZipArchive::new(&mut temp2)?;
-    match file {
-        None => archive.extract(path)?,
-        Some(file) => {
-            let mut output = File::create(Path::new(&path).join(&file))?;
-            let mut file = archive.by_name(&file)?;
-            std::io::copy(&mut file, &mut output)?;
+async fn restore(params: RestoreParams) -> Result<(), BoxError> {
+    let RestoreParams {
+        path, storage, key, file, encryption, chunk_size, concurrency, retries, state_dir, no_resume, no_xattrs, owners,
+    } = params;
+    let owners = owners.map();
+    let download_config = DownloadConfig { chunk_size, concurrency, retries };
+    let backend = storage.open().await?;
+    let keys = encryption.keys(&backend, &[], true).await?.map(Arc::new);
+    let mut manifest = Manifest::load(&backend, &key, keys.as_deref()).await?;
+    if no_xattrs {
+        for entry in manifest.iter_mut().flat_map(|manifest| manifest.entries.values_mut()) {
+            entry.xattrs.clear();
         }
     }
+
+    if let Some(manifest) = manifest.as_ref().filter(|manifest| manifest.repository) {
+        return restore_from_repository(&backend, manifest, &path, file.as_deref(), keys, &owners, download_config).await;
+    }
+
+    if let Some(file) = file {
+        let entry = match &manifest {
+            Some(manifest) => Some(
+                manifest.entries
+                    .get(&file)
+                    .filter(|entry| entry.kind != EntryKind::Dir)
+                    .ok_or_else(|| format!("{file} is not a file in {key}"))?,
+            ),
+            None => None,
+        };
+        // Only one name of a hardlinked file holds its contents.
+        let source = entry.and_then(|entry| entry.link.clone()).unwrap_or_else(|| file.clone());
+        let source_entry = match (&manifest, entry) {
+            (Some(manifest), Some(entry)) => Some(manifest.entries.get(&source).unwrap_or(entry)),
+            _ => None,
+        };
+        let archive_key = source_entry.map_or(&key, |entry| &entry.snapshot);
+        let layout = match &manifest {
+            Some(manifest) if manifest.format == Format::Tar => {
+                Layout::Tar { compression: manifest.compression.unwrap_or_default(), xattrs: !no_xattrs }
+            }
+            _ => Layout::Zip(source_entry.cloned().map(Box::new)),
+        };
+        let file = SingleFile { name: file, source, layout, owners };
+        return restore_file(backend.clone(), archive_key, &path, file, keys, retries).await;
+    }
+
+    let temp_state_dir;
+    let state_dir = match state_dir {
+        _ if no_resume => {
+            temp_state_dir = tempfile::tempdir()?;
+            temp_state_dir.path().to_path_buf()
+        }
+        Some(state_dir) => state_dir,
+        None => default_state_dir()?,
+    };
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => {
+            let state = fetch_archive(&backend, &storage.bucket, &key, download_config, &state_dir).await?;
+            ZipArchive::new(ObjectReader::new(File::open(&state.archive)?, keys.as_deref(), &key)?)?.extract(&path)?;
+            return Ok(state.remove()?);
+        }
+    };
+
+    if manifest.format == Format::Tar {
+        let compression = manifest.compression.unwrap_or_default();
+        let mut unpacker = Unpacker::new(&path, !no_xattrs, owners);
+        for snapshot in manifest.snapshots() {
+            let state = fetch_archive(&backend, &storage.bucket, snapshot, download_config, &state_dir).await?;
+            let archive = ObjectReader::new(File::open(&state.archive)?, keys.as_deref(), snapshot)?;
+            unpacker.unpack(BufReader::new(archive), compression, None, |name| {
+                let entry = manifest.entries.get(name)?;
+                (entry.snapshot == snapshot && entry.link.is_none()).then(|| name.to_string())
+            })?;
+            state.remove()?;
+        }
+        manifest.restore_hardlinks(Path::new(&path))?;
+        return Ok(unpacker.finish()?);
+    }
+
+    let mut dirs = Vec::new();
+    for snapshot in manifest.snapshots() {
+        let state = fetch_archive(&backend, &storage.bucket, snapshot, download_config, &state_dir).await?;
+        let mut archive = ZipArchive::new(ObjectReader::new(File::open(&state.archive)?, keys.as_deref(), snapshot)?)?;
+        let entries = manifest.entries.iter().filter(|(_, entry)| entry.snapshot == snapshot && entry.link.is_none());
+        for (name, entry) in entries {
+            let output = entry_path(Path::new(&path), name)?;
+            if entry.kind == EntryKind::Dir {
+                fs::create_dir_all(&output)?;
+                let owner = entry.owner.as_ref().and_then(|owner| owners.resolve(owner));
+                let mode = match entry.mode {
+                    Some(mode) => Some(mode),
+                    None => archive.by_name(&format!("{name}/"))?.unix_mode(),
+                };
+                dirs.push((output, owner, mode, entry.modified(), entry.xattrs()?));
+                continue;
+            }
+            let owner = entry.owner.as_ref().and_then(|owner| owners.resolve(owner));
+            let mut zip_entry = archive.by_name(name)?;
+            extract_entry(&mut zip_entry, &output, owner, entry.mode, entry.modified(), &entry.xattrs()?, &entry.extents)?;
+        }
+        state.remove()?;
+    }
+    manifest.restore_hardlinks(Path::new(&path))?;
+    // Restoring a directory's contents changes its mtime, so directories come last, innermost
+    // first.
+    for (output, owner, mode, modified, xattrs) in dirs.into_iter().rev() {
+        set_metadata(&output, owner, mode, modified, &xattrs)?;
+    }
+
     Ok(())
 }
 
-async fn get_client() -> Client {
-    let region = Region::new("us-east-1");
-    let shared_config = aws_config::from_env().region(region.clone()).load().await;
-    Client::new(&shared_config)
+/// Downloads the archive stored under `key` into `state_dir`, resuming an earlier
+/// interrupted download of it if there is one. The returned state has to be removed once
+/// the archive is no longer needed.
+async fn fetch_archive(
+    backend: &Arc<dyn Storage>,
+    bucket: &str,
+    key: &str,
+    config: DownloadConfig,
+    state_dir: &Path,
+) -> Result<RestoreState, BoxError> {
+    let mut state = RestoreState::load_or_new(state_dir, bucket, key)?;
+    let archive = state.archive.clone();
+    let mut download = state.download.take();
+    download_ranges(backend, key, &archive, config, &mut download, |progress| {
+        state.download = Some(progress.clone());
+        Ok(state.save()?)
+    }).await?;
+    Ok(state)
 }
 
-pub async fn download_object(
-    client: &Client,
-    bucket_name: &str,
-    key: &str,
-) -> Result<GetObjectOutput, SdkError<GetObjectError>> {
-    client
-        .get_object()
-        .bucket(bucket_name)
-        .key(key)
-        .send()
-        .await
-}
-
-pub async fn upload_object(
-    client: &Client,
-    bucket_name: &str,
-    file_name: &Path,
+/// How the archive a single file is restored from is laid out.
+enum Layout {
+    /// A zip archive, with the manifest entry holding the file's contents if the snapshot has
+    /// a manifest.
+    Zip(Option<Box<ManifestEntry>>),
+    /// A tar archive with its compression, and whether to restore extended attributes.
+    Tar { compression: Compression, xattrs: bool },
+}
+
+/// A single file to restore as `name` from the archive entry `source`, which is another name
+/// of the same file if it's hardlinked, with its owner mapped by `owners`.
+struct SingleFile {
+    name: String,
+    source: String,
+    layout: Layout,
+    owners: OwnerMap,
+}
+
+/// Restores a single entry by reading the archive's central directory and that entry's bytes
+/// with ranged requests, without downloading the rest of the archive. A tar archive has no
+/// directory and is read up to the entry instead.
+async fn restore_file(
+    backend: Arc<dyn Storage>,
     key: &str,
-) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
-    let body = ByteStream::from_path(file_name).await;
-    client
-        .put_object()
-        .bucket(bucket_name)
-        .key(key)
-        .body(body.unwrap())
-        .send()
-        .await
-}
-
-pub async fn create_bucket_if_not_exists(
-    client: &Client,
-    bucket_name: &str,
-) -> Result<(), SdkError<CreateBucketError>> {
-    if let Ok(_) = client.head_bucket().bucket(bucket_name).send().await {
-        return Ok(());
+    path: &str,
+    file: SingleFile,
+    keys: Option<Arc<Keys>>,
+    retries: u32,
+) -> Result<(), BoxError> {
+    let (size, e_tag) = backend.object_info(key).await?.ok_or_else(|| not_found(key))?;
+    let reader = RangedReader::new(Handle::current(), backend, key, size, e_tag, retries);
+    let output = entry_path(Path::new(path), &file.name)?;
+    let path = path.to_string();
+    let key = key.to_string();
+    tokio::task::spawn_blocking(move || -> Result<(), BoxError> {
+        let SingleFile { name, source, layout, owners } = file;
+        let entry = match layout {
+            Layout::Zip(entry) => entry,
+            Layout::Tar { compression, xattrs } => {
+                let archive = ObjectReader::new(reader, keys.as_deref(), &key)?;
+                let mut unpacker = Unpacker::new(&path, xattrs, owners);
+                let select = |entry: &str| (entry == source).then(|| name.clone());
+                if unpacker.unpack(BufReader::new(archive), compression, Some(1), select)? == 0 {
+                    return Err(format!("{source} is not in {key}").into());
+                }
+                return Ok(unpacker.finish()?);
+            }
+        };
+        let mut archive = ZipArchive::new(ObjectReader::new(reader, keys.as_deref(), &key)?)?;
+        let mut zip_entry = archive.by_name(&source)?;
+        // Snapshots without a manifest only have the entry's own time, to two seconds.
+        let (owner, mode, modified, xattrs, extents) = match entry {
+            Some(entry) => {
+                let owner = entry.owner.as_ref().and_then(|owner| owners.resolve(owner));
+                (owner, entry.mode, entry.modified(), entry.xattrs()?, entry.extents)
+            }
+            None => {
+                let modified = zip_entry.last_modified().to_time().map_or_else(|_| SystemTime::now(), SystemTime::from);
+                (None, None, modified, Vec::new(), Vec::new())
+            }
+        };
+        Ok(extract_entry(&mut zip_entry, &output, owner, mode, modified, &xattrs, &extents)?)
+    }).await?
+}
+
+fn keygen(params: KeygenParams) -> Result<(), BoxError> {
+    println!("{}", generate_identity(&params.output)?);
+    Ok(())
+}
+
+async fn key(args: KeyArgs) -> Result<(), BoxError> {
+    match args {
+        KeyArgs::Add(params) => {
+            let backend = params.keyring.storage.open().await?;
+            let passphrase = params.keyring.passphrase.as_deref();
+            println!("{}", keyring::add(&backend, passphrase, &params.new_passphrase, params.label).await?);
+        }
+        KeyArgs::List(params) => {
+            let backend = params.storage.open().await?;
+            for key in keyring::list(&backend).await? {
+                println!("{}\t{}\t{}", key.id, key.created, key.label.unwrap_or_default());
+            }
+        }
+        KeyArgs::Remove(params) => {
+            let backend = params.keyring.storage.open().await?;
+            keyring::remove(&backend, &params.keyring.required_passphrase()?, &params.id).await?;
+        }
+        KeyArgs::ChangePassphrase(params) => {
+            let backend = params.keyring.storage.open().await?;
+            let passphrase = params.keyring.required_passphrase()?;
+            println!("{}", keyring::change_passphrase(&backend, &passphrase, &params.new_passphrase).await?);
+        }
+    }
+    Ok(())
+}
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/// The per-user directory state is kept in unless `--state-dir` is given.
+fn default_state_dir() -> Result<PathBuf, BoxError> {
+    let dir = dirs::state_dir().or_else(dirs::cache_dir).ok_or("no per-user state directory, use --state-dir")?;
+    Ok(dir.join("aws-backup"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    use super::*;
+
+    /// A tree to back up along with local storage and a state directory, all in a temporary
+    /// directory, and `options` given to every backup and restore.
+    struct Setup {
+        dir: TempDir,
+        options: Vec<String>,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let setup = Setup { dir: tempfile::tempdir().unwrap(), options: Vec::new() };
+            fs::create_dir(setup.src()).unwrap();
+            setup
+        }
+
+        /// Encrypts and decrypts with a key file.
+        fn encrypted() -> Self {
+            let mut setup = Setup::new();
+            fs::write(setup.path("key"), [7; 32]).unwrap();
+            setup.options = vec!["--key-file".to_string(), setup.path("key")];
+            setup
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.dir.path().join(name).to_str().unwrap().to_string()
+        }
+
+        fn src(&self) -> PathBuf {
+            self.dir.path().join("src")
+        }
+
+        fn write(&self, name: &str, contents: &[u8]) {
+            let path = self.src().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn chmod(&self, name: &str, mode: u32) {
+            fs::set_permissions(self.src().join(name), fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        async fn run(&self, command: &str, path: &str, key: &str, extra: &[&str]) -> Result<(), BoxError> {
+            let (bucket, state) = (self.path("bucket"), self.path("state"));
+            let args = ["aws-backup", command, "-p", path, "--storage", "local", "-b", &bucket, "-k", key, "--state-dir", &state];
+            let options = self.options.iter().map(String::as_str);
+            run(Args::try_parse_from(args.into_iter().chain(options).chain(extra.iter().copied()))?).await
+        }
+
+        async fn backup(&self, key: &str, extra: &[&str]) {
+            self.run("backup", &self.path("src"), key, extra).await.unwrap();
+        }
+
+        /// Restores `key` into a directory of its own and returns that directory's tree.
+        async fn restore(&self, key: &str) -> Vec<Node> {
+            let output = self.path(&format!("restored-{key}"));
+            self.run("restore", &output, key, &[]).await.unwrap();
+            tree(Path::new(&output))
+        }
+
+        /// Backs up the tree as `key` and checks it restores the same.
+        async fn check_round_trip(&self, key: &str, extra: &[&str]) {
+            self.backup(key, extra).await;
+            assert_eq!(self.restore(key).await, tree(&self.src()), "{key}");
+        }
+    }
+
+    /// Each way of storing a snapshot, with the options choosing it.
+    const FORMATS: [(&str, &[&str]); 3] = [("zip", &[]), ("tar", &["--format", "tar"]), ("repository", &["--repository"])];
+
+    /// Name, mode, mtime and contents or link target of an entry in a tree.
+    type Node = (PathBuf, u32, i64, Vec<u8>);
+
+    /// Everything below `root`, without `root` itself, in name order.
+    fn tree(root: &Path) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for entry in WalkDir::new(root).min_depth(1).sort_by_file_name() {
+            let entry = entry.unwrap();
+            let metadata = entry.path().symlink_metadata().unwrap();
+            let contents = match metadata.file_type() {
+                file_type if file_type.is_file() => fs::read(entry.path()).unwrap(),
+                file_type if file_type.is_symlink() => fs::read_link(entry.path()).unwrap().as_os_str().as_bytes().to_vec(),
+                _ => Vec::new(),
+            };
+            let name = entry.path().strip_prefix(root).unwrap().to_path_buf();
+            nodes.push((name, metadata.mode(), metadata.mtime(), contents));
+        }
+        nodes
+    }
+
+    /// `len` bytes that don't compress to nothing.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// `len` bytes that don't compress at all.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn round_trips_through_local_storage() {
+        let setup = Setup::new();
+        setup.write("a.txt", b"hello");
+        setup.write("dir/b.bin", &data(200_000));
+        fs::create_dir(setup.src().join("dir/empty")).unwrap();
+        setup.check_round_trip("zip", &[]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_state_readable_by_the_owner_only() {
+        let setup = Setup::new();
+        setup.write("a.txt", b"hello");
+        setup.backup("zip", &[]).await;
+        let mode = fs::metadata(setup.path("state")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn caches_manifests_readable_by_the_owner_only() {
+        let setup = Setup::new();
+        setup.write("a.txt", b"hello");
+        let recipient = generate_identity(Path::new(&setup.path("identity"))).unwrap();
+        setup.backup("zip", &["--recipient", &recipient]).await;
+        let cached = manifest_cache(Path::new(&setup.path("state")), &setup.path("bucket"), "zip");
+        assert_eq!(fs::metadata(cached).unwrap().mode() & 0o777, 0o600);
     }
-    client
-        .create_bucket()
-        .bucket(bucket_name)
-        .send()
-        .await
-        .map(drop)
-}
-
-fn zip_dir<T: Write + Seek>(src: &str, dst: T, method: zip::CompressionMethod) -> zip::result::ZipResult<()> {
-    fn helper<T: Write + Seek, I: Iterator<Item=DirEntry>>(
-        it: &mut I,
-        prefix: &str,
-        writer: T,
-        method: zip::CompressionMethod
-    ) -> zip::result::ZipResult<()> {
-        let mut zip = ZipWriter::new(writer);
-        let options = FileOptions::default()
-            .compression_method(method)
-            .unix_permissions(0o755);
-
-        let mut buffer = Vec::new();
-        for entry in it {
-            let path = entry.path();
-            let name = path.strip_prefix(Path::new(prefix)).unwrap();
-
-            if path.is_file() {
-                zip.start_file(name.to_str().unwrap(), options)?;
-                let mut f = File::open(path)?;
-
-                f.read_to_end(&mut buffer)?;
-                zip.write_all(&*buffer)?;
-                buffer.clear();
-            } else if name.as_os_str().len() != 0 {
-                zip.add_directory(name.to_str().unwrap(), options)?;
+
+    /// Backs up a tree, then two increments of it, each changing contents, modes and which
+    /// files there are, and checks each snapshot restores the tree as it was backed up.
+    async fn incremental_round_trip(setup: &Setup, extra: &[&str]) {
+        setup.write("a.txt", b"hello");
+        setup.write("dir/b.bin", &data(200_000));
+        setup.write("dir/run.sh", b"#!/bin/sh\n");
+        setup.chmod("dir/run.sh", 0o755);
+        fs::create_dir(setup.src().join("dir/empty")).unwrap();
+        setup.backup("full", extra).await;
+        let full = tree(&setup.src());
+
+        setup.write("a.txt", b"hello again");
+        fs::remove_file(setup.src().join("dir/run.sh")).unwrap();
+        setup.write("dir/new.txt", b"new");
+        setup.chmod("dir/b.bin", 0o600);
+        setup.backup("first", &[extra, &["--parent", "full"]].concat()).await;
+        let first = tree(&setup.src());
+
+        setup.write("dir/new.txt", b"newer");
+        setup.write("dir/empty/c.txt", b"c");
+        setup.backup("second", &[extra, &["--parent", "first"]].concat()).await;
+        let second = tree(&setup.src());
+
+        assert_eq!(setup.restore("full").await, full);
+        assert_eq!(setup.restore("first").await, first);
+        assert_eq!(setup.restore("second").await, second);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn zip_incremental_round_trip() {
+        let setup = Setup::new();
+        incremental_round_trip(&setup, &[]).await;
+        let second = ZipArchive::new(File::open(setup.path("bucket/second")).unwrap()).unwrap();
+        let mut files: Vec<_> = second.file_names().filter(|name| !name.ends_with('/')).collect();
+        files.sort();
+        assert_eq!(files, ["dir/empty/c.txt", "dir/new.txt"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repository_incremental_round_trip() {
+        incremental_round_trip(&Setup::new(), &["--repository"]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repository_stores_identical_chunks_once() {
+        let setup = Setup::new();
+        let noise = noise(3_000_000);
+        setup.write("a.bin", &noise);
+        setup.write("copy/a.bin", &noise);
+        setup.check_round_trip("repository", &["--repository"]).await;
+
+        let manifest: Manifest = serde_json::from_slice(&fs::read(setup.path("bucket/repository.manifest.json")).unwrap()).unwrap();
+        let chunks = &manifest.entries["a.bin"].chunks;
+        assert!(chunks.len() > 1);
+        assert_eq!(&manifest.entries["copy/a.bin"].chunks, chunks);
+        let stored = WalkDir::new(setup.path("bucket/chunks")).into_iter().filter(|entry| entry.as_ref().unwrap().file_type().is_file());
+        assert_eq!(stored.count(), chunks.len());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_zip_incremental_round_trip() {
+        incremental_round_trip(&Setup::encrypted(), &[]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_repository_incremental_round_trip() {
+        incremental_round_trip(&Setup::encrypted(), &["--repository"]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn names_encrypted_chunks_without_revealing_their_contents() {
+        for setup in [Setup::new(), Setup::encrypted()] {
+            setup.write("a.txt", b"known contents");
+            setup.check_round_trip("repository", &["--repository"]).await;
+
+            let chunks: Vec<_> = WalkDir::new(setup.path("bucket/chunks")).min_depth(2).into_iter().collect();
+            let name = chunks[0].as_ref().unwrap().file_name().to_str().unwrap().to_string();
+            let plain = format!("{:x}", Sha256::digest(b"known contents"));
+            assert_eq!(name == plain, setup.options.is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_backups_need_the_key() {
+        let mut setup = Setup::encrypted();
+        setup.write("a.txt", b"hello");
+        setup.backup("zip", &[]).await;
+        setup.options.clear();
+        assert!(setup.run("restore", &setup.path("restored"), "zip", &[]).await.is_err());
+        assert!(!Path::new(&setup.path("restored/a.txt")).exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tar_incremental_round_trip() {
+        incremental_round_trip(&Setup::new(), &["--format", "tar", "--compression", "zstd"]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_full_modes_and_mode_only_changes() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            setup.write("setuid", b"a");
+            setup.chmod("setuid", 0o4755);
+            setup.write("setgid", b"b");
+            setup.chmod("setgid", 0o2750);
+            setup.write("private/c", b"c");
+            setup.chmod("private", 0o700);
+            fs::create_dir(setup.src().join("sticky")).unwrap();
+            setup.chmod("sticky", 0o1777);
+            setup.check_round_trip(format, extra).await;
+
+            setup.chmod("setuid", 0o755);
+            setup.chmod("private/c", 0o400);
+            setup.check_round_trip(&format!("{format}-chmod"), &[extra, &["--parent", format]].concat()).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_symlinks_as_links() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            setup.write("dir/a.txt", b"a");
+            symlink("a.txt", setup.src().join("dir/link")).unwrap();
+            symlink("dir", setup.src().join("dir-link")).unwrap();
+            symlink("missing", setup.src().join("dangling")).unwrap();
+            symlink("/nonexistent/target", setup.src().join("absolute")).unwrap();
+            setup.check_round_trip(format, extra).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn follows_or_skips_symlinks() {
+        let setup = Setup::new();
+        setup.write("dir/a.txt", b"a");
+        symlink("dir", setup.src().join("dir-link")).unwrap();
+        symlink("..", setup.src().join("dir/loop")).unwrap();
+        let names = |tree: Vec<Node>| tree.into_iter().map(|(name, mode, ..)| (name.to_str().unwrap().to_string(), mode & 0o170000)).collect::<Vec<_>>();
+
+        setup.backup("follow", &["--symlinks", "follow"]).await;
+        let expected = [("dir", 0o040000), ("dir/a.txt", 0o100000), ("dir-link", 0o040000), ("dir-link/a.txt", 0o100000)];
+        assert_eq!(names(setup.restore("follow").await), expected.map(|(name, kind)| (name.to_string(), kind)));
+
+        setup.backup("skip", &["--symlinks", "skip"]).await;
+        let expected = [("dir", 0o040000), ("dir/a.txt", 0o100000)];
+        assert_eq!(names(setup.restore("skip").await), expected.map(|(name, kind)| (name.to_string(), kind)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_hardlinks_as_links() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            setup.write("a.bin", &data(100_000));
+            fs::hard_link(setup.src().join("a.bin"), setup.src().join("b.bin")).unwrap();
+            setup.write("dir/c.txt", b"c");
+            fs::hard_link(setup.src().join("a.bin"), setup.src().join("dir/d.bin")).unwrap();
+            setup.check_round_trip(format, extra).await;
+
+            let restored = PathBuf::from(setup.path(&format!("restored-{format}")));
+            let inode = |name: &str| restored.join(name).metadata().unwrap().ino();
+            assert_eq!(inode("b.bin"), inode("a.bin"), "{format}");
+            assert_eq!(inode("dir/d.bin"), inode("a.bin"), "{format}");
+            assert_eq!(restored.join("a.bin").metadata().unwrap().nlink(), 3, "{format}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stores_hardlinks_in_tar_archives_as_links() {
+        let setup = Setup::new();
+        setup.write("a.bin", &data(100_000));
+        fs::hard_link(setup.src().join("a.bin"), setup.src().join("b.bin")).unwrap();
+        setup.backup("tar", &["--format", "tar", "--compression", "store"]).await;
+
+        let extracted = setup.path("extracted");
+        tar::Archive::new(File::open(setup.path("bucket/tar")).unwrap()).unpack(&extracted).unwrap();
+        let inode = |name: &str| Path::new(&extracted).join(name).metadata().unwrap().ino();
+        assert_eq!(inode("b.bin"), inode("a.bin"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_mtimes_to_the_nanosecond() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            setup.write("dir/a.txt", b"a");
+            let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+            filetime::set_file_mtime(setup.src().join("dir/a.txt"), mtime).unwrap();
+            filetime::set_file_mtime(setup.src().join("dir"), mtime).unwrap();
+            setup.check_round_trip(format, extra).await;
+
+            let restored = PathBuf::from(setup.path(&format!("restored-{format}")));
+            for name in ["dir", "dir/a.txt"] {
+                let metadata = restored.join(name).metadata().unwrap();
+                assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), mtime, "{format} {name}");
             }
         }
-        zip.finish()?;
-        Ok(())
     }
 
-    if !Path::new(src).is_dir() {
-        return Err(ZipError::FileNotFound);
+    #[test]
+    fn leaves_out_files_removed_while_being_backed_up() {
+        let setup = Setup::new();
+        setup.write("a.txt", b"a");
+        fs::hard_link(setup.src().join("a.txt"), setup.src().join("b.txt")).unwrap();
+        let (a, b) = (setup.src().join("a.txt"), setup.src().join("b.txt"));
+        let metadata = a.metadata().unwrap();
+
+        let mut builder = ManifestBuilder::new("key", None, None, false, false);
+        assert!(builder.select("a.txt", &a, &metadata).unwrap());
+        assert!(!builder.select("b.txt", &b, &b.metadata().unwrap()).unwrap());
+        builder.forget("a.txt");
+        fs::remove_file(&a).unwrap();
+        assert!(crate::archive::open_source(&a, &metadata, false).unwrap().is_none());
+        assert!(!builder.select("a.txt", &a, &metadata).unwrap());
+        assert!(builder.finish().entries.is_empty());
     }
 
-    let walkdir = WalkDir::new(src.to_string());
-    let it = walkdir.into_iter();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_sparse_files_with_holes() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            let mut file = File::create(setup.src().join("sparse")).unwrap();
+            std::io::Write::write_all(&mut file, b"start").unwrap();
+            file.set_len(16 * 1024 * 1024).unwrap();
+            setup.check_round_trip(format, extra).await;
 
-    helper(&mut it.filter_map(|e| e.ok()), src, dst, method)?;
+            let restored = Path::new(&setup.path(&format!("restored-{format}"))).join("sparse").metadata().unwrap();
+            assert!(restored.blocks() * 512 < 1024 * 1024, "{format}");
+        }
+    }
 
-    Ok(())
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_a_single_file_from_a_chain() {
+        let setups = FORMATS.into_iter().map(|format| (Setup::new(), format));
+        for (setup, (format, extra)) in setups.chain([(Setup::encrypted(), FORMATS[0])]) {
+            setup.write("a.txt", b"a");
+            setup.write("dir/big.bin", &noise(3_000_000));
+            setup.write("dir/other.txt", b"other");
+            setup.backup("full", extra).await;
+            setup.write("dir/other.txt", b"changed");
+            setup.backup("second", &[extra, &["--parent", "full"]].concat()).await;
+
+            for name in ["dir/big.bin", "dir/other.txt"] {
+                let output = setup.path(&format!("single-{}", name.replace('/', "-")));
+                setup.run("restore", &output, "second", &["-f", name]).await.unwrap();
+                let restored = tree(Path::new(&output));
+                let names: Vec<_> = restored.iter().map(|(name, ..)| name.to_str().unwrap()).collect();
+                assert_eq!(names, ["dir", name], "{format}");
+                let source = tree(&setup.src()).into_iter().find(|(source, ..)| source == Path::new(name));
+                assert_eq!(restored.last(), source.as_ref(), "{format} {name}");
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refuses_to_restore_through_restored_symlinks() {
+        for (format, extra) in FORMATS {
+            let setup = Setup::new();
+            let outside = setup.dir.path().join("outside");
+            fs::create_dir(&outside).unwrap();
+            symlink(&outside, setup.src().join("x")).unwrap();
+            setup.backup("link", extra).await;
+            fs::remove_file(setup.src().join("x")).unwrap();
+            setup.write("x/job", b"job");
+            setup.backup("next", extra).await;
+
+            // A forged manifest restoring the link from the first snapshot ahead of the file
+            // below it from the second.
+            let manifest = |key: &str| -> Manifest {
+                serde_json::from_slice(&fs::read(setup.path(&format!("bucket/{key}.manifest.json"))).unwrap()).unwrap()
+            };
+            let mut forged = manifest("next");
+            forged.entries.insert("x".to_string(), manifest("link").entries["x"].clone());
+            fs::write(setup.path("bucket/next.manifest.json"), serde_json::to_vec(&forged).unwrap()).unwrap();
+
+            assert!(setup.run("restore", &setup.path("restored"), "next", &[]).await.is_err(), "{format}");
+            // The link the refused restore left behind is in the way of restoring the file alone.
+            assert!(setup.run("restore", &setup.path("restored"), "next", &["-f", "x/job"]).await.is_err(), "{format}");
+            assert!(!outside.join("job").exists(), "{format}");
+        }
+    }
 }
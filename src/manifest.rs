@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uzers::UsersCache;
+
+use crate::BoxError;
+use crate::archive::{create_hardlink, entry_path, file_mode, link_target, read_xattrs, warn_vanished, Compression, Format};
+use crate::crypto::{open, Keys};
+use crate::owner::Owner;
+use crate::sparse::data_extents;
+use crate::storage::Storage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub inode: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Key of the snapshot whose archive holds this entry's contents.
+    pub snapshot: String,
+    /// Hashes of the chunks making up the file, in order, for repository snapshots.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// What a symlink points to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Name of the entry holding the contents of a file hardlinked to it, for every name of a
+    /// hardlinked file but the first one walked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    /// Extended attributes, including POSIX ACLs and file capabilities, with hex encoded values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// Who owned the entry; snapshots from before this was recorded restore entries as owned
+    /// by whoever restores them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+    /// Permission bits along with the setuid, setgid and sticky bits, which zip entries can't
+    /// hold; snapshots from before this was recorded take modes from their archives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Data regions of a sparse file as (offset, length) pairs; everything in between is a hole.
+    /// Tar archives and repositories store only these, back to back, while zip archives store
+    /// files whole, holes and all, so other tools extract them right.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extents: Vec<(u64, u64)>,
+}
+
+impl ManifestEntry {
+    /// When the entry was last modified, to the nanosecond.
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.mtime.max(0) as u64, self.mtime_nsec)
+    }
+
+    /// The entry's extended attributes, as `read_xattrs` returned them.
+    pub fn xattrs(&self) -> io::Result<Vec<(String, Vec<u8>)>> {
+        self.xattrs
+            .iter()
+            .map(|(key, value)| {
+                let value = hex::decode(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// The complete state of a backed up tree at one snapshot. Files that haven't changed since
+/// the parent snapshot point at the older archive holding them, so restoring any snapshot
+/// only needs its own manifest.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Whether file contents are stored as deduplicated chunks rather than in archives.
+    #[serde(default)]
+    pub repository: bool,
+    /// How this snapshot's files are packed into its archive; snapshots from before this was
+    /// recorded are zip archives.
+    #[serde(default)]
+    pub format: Format,
+    /// How this snapshot's archive is compressed; snapshots from before this was recorded
+    /// used deflate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    pub parent: Option<String>,
+    pub entries: BTreeMap<String, ManifestEntry>,
+    /// Entries of the parent snapshot that no longer exist.
+    pub deleted: Vec<String>,
+}
+
+/// Key the manifest of the snapshot stored under `key` is kept at.
+pub fn manifest_key(key: &str) -> String {
+    format!("{key}.manifest.json")
+}
+
+impl Manifest {
+    /// Loads the manifest of the snapshot stored under `key`, decrypting it with `keys`, or
+    /// `None` if the snapshot was made before manifests existed.
+    pub async fn load(storage: &Arc<dyn Storage>, key: &str, keys: Option<&Keys>) -> Result<Option<Manifest>, BoxError> {
+        let key = manifest_key(key);
+        if storage.object_info(&key).await?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&open(keys, &key, storage.get(&key).await?)?)?))
+    }
+
+    /// Keys of the snapshots whose archives hold this snapshot's entries.
+    pub fn snapshots(&self) -> BTreeSet<&str> {
+        self.entries
+            .values()
+            .filter(|entry| entry.link.is_none())
+            .map(|entry| entry.snapshot.as_str())
+            .collect()
+    }
+
+    /// Links every name of a hardlinked file other than the one holding its contents to that
+    /// one, once the snapshot's files have been restored under `path`.
+    pub fn restore_hardlinks(&self, path: &Path) -> io::Result<()> {
+        for (name, entry) in &self.entries {
+            if let Some(target) = &entry.link {
+                create_hardlink(&entry_path(path, target)?, &entry_path(path, name)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the manifest of a new snapshot while its tree is being archived, deciding which
+/// files changed since `parent` and so have to be stored again.
+pub struct ManifestBuilder<'a> {
+    key: String,
+    parent: Option<&'a Manifest>,
+    hash: bool,
+    manifest: Manifest,
+    /// First name walked of each hardlinked file, by device and inode.
+    links: HashMap<(u64, u64), String>,
+    names: UsersCache,
+}
+
+impl<'a> ManifestBuilder<'a> {
+    /// With `hash`, files are compared by size and SHA-256 of their contents instead of size,
+    /// modification time and inode.
+    /// With `repository`, the snapshot stores files as chunks and `parent` has to be a
+    /// repository snapshot too.
+    pub fn new(
+        key: &str,
+        parent_key: Option<&str>,
+        parent: Option<&'a Manifest>,
+        hash: bool,
+        repository: bool,
+    ) -> Self {
+        ManifestBuilder {
+            key: key.to_string(),
+            parent,
+            hash,
+            manifest: Manifest { repository, parent: parent_key.map(str::to_string), ..Manifest::default() },
+            links: HashMap::new(),
+            names: UsersCache::new(),
+        }
+    }
+
+    /// Records an entry and returns whether its contents have to go in the new archive. Only
+    /// the first name walked of a hardlinked file is stored; the others link to it. An entry
+    /// removed since it was walked is left out with a warning.
+    pub fn select(&mut self, name: &str, path: &Path, metadata: &Metadata) -> io::Result<bool> {
+        match self.record(name, path, metadata) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn_vanished(path);
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    /// Does the work of `select`, reading everything about the entry before recording it, so
+    /// nothing is recorded if that fails.
+    fn record(&mut self, name: &str, path: &Path, metadata: &Metadata) -> io::Result<bool> {
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let kind = match metadata.file_type() {
+            file_type if file_type.is_dir() => EntryKind::Dir,
+            file_type if file_type.is_symlink() => EntryKind::Symlink,
+            _ => EntryKind::File,
+        };
+        let mut entry = ManifestEntry {
+            kind,
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            mtime: modified.as_secs() as i64,
+            mtime_nsec: modified.subsec_nanos(),
+            inode: inode(metadata),
+            hash: None,
+            snapshot: self.key.clone(),
+            chunks: Vec::new(),
+            target: None,
+            link: None,
+            xattrs: BTreeMap::new(),
+            owner: Owner::of(metadata, &self.names),
+            mode: Some(file_mode(metadata) & 0o7777),
+            extents: Vec::new(),
+        };
+        let id = hardlink_id(metadata);
+        if let Some(first) = id.and_then(|id| self.links.get(&id)) {
+            entry.link = Some(first.clone());
+            self.manifest.entries.insert(name.to_string(), entry);
+            return Ok(false);
+        }
+        entry.xattrs = read_xattrs(path)?.into_iter().map(|(key, value)| (key, hex::encode(value))).collect();
+        match entry.kind {
+            EntryKind::File => {
+                entry.extents = data_extents(path, metadata)?;
+                if self.hash {
+                    entry.hash = Some(hash_file(path)?);
+                }
+            }
+            EntryKind::Symlink => entry.target = Some(link_target(path)?),
+            EntryKind::Dir => {}
+        }
+        if let Some(id) = id {
+            self.links.insert(id, name.to_string());
+        }
+
+        let previous = self.parent.and_then(|parent| parent.entries.get(name));
+        let unchanged = match previous {
+            Some(previous) if previous.kind == entry.kind && entry.kind != EntryKind::Dir => {
+                // A name that only linked to another one has no contents of its own to reuse,
+                // and archives keep modes, and tar archives owners and extended attributes,
+                // along with the contents.
+                if previous.target != entry.target
+                    || previous.link.is_some()
+                    || previous.xattrs != entry.xattrs
+                    || previous.owner.is_some() && previous.owner != entry.owner
+                    || previous.mode.is_some() && previous.mode != entry.mode
+                {
+                    false
+                } else if self.hash {
+                    previous.size == entry.size && previous.hash == entry.hash
+                } else {
+                    previous.size == entry.size
+                        && previous.mtime == entry.mtime
+                        && previous.mtime_nsec == entry.mtime_nsec
+                        && previous.inode == entry.inode
+                }
+            }
+            _ => false,
+        };
+        if unchanged {
+            let previous = previous.unwrap();
+            entry.snapshot = previous.snapshot.clone();
+            entry.hash = entry.hash.or_else(|| previous.hash.clone());
+            entry.chunks = previous.chunks.clone();
+            entry.extents = previous.extents.clone();
+        }
+        self.manifest.entries.insert(name.to_string(), entry);
+        Ok(!unchanged)
+    }
+
+    /// The name `name` was selected as a hardlink to, if that one is stored in this snapshot.
+    pub fn stored_link(&self, name: &str) -> Option<String> {
+        let first = self.manifest.entries.get(name)?.link.as_ref()?;
+        (self.manifest.entries.get(first)?.snapshot == self.key).then(|| first.clone())
+    }
+
+    /// Records how the snapshot's archive is packed and compressed.
+    pub fn set_format(&mut self, format: Format, compression: Compression) {
+        self.manifest.format = format;
+        self.manifest.compression = Some(compression);
+    }
+
+    /// Data regions of the sparse file `name` to store, or none to store all of it.
+    pub fn extents(&self, name: &str) -> Vec<(u64, u64)> {
+        self.manifest.entries.get(name).map(|entry| entry.extents.clone()).unwrap_or_default()
+    }
+
+    /// Leaves out a selected file that was removed before its contents could be stored, along
+    /// with the other names linking to it.
+    pub fn forget(&mut self, name: &str) {
+        self.manifest.entries.remove(name);
+        self.manifest.entries.retain(|_, entry| entry.link.as_deref() != Some(name));
+        self.links.retain(|_, first| first != name);
+    }
+
+    /// Records the chunks a selected file was stored as.
+    pub fn set_chunks(&mut self, name: &str, chunks: Vec<String>) {
+        if let Some(entry) = self.manifest.entries.get_mut(name) {
+            entry.chunks = chunks;
+        }
+    }
+
+    pub fn finish(mut self) -> Manifest {
+        if let Some(parent) = self.parent {
+            self.manifest.deleted = parent.entries
+                .keys()
+                .filter(|name| !self.manifest.entries.contains_key(*name))
+                .cloned()
+                .collect();
+        }
+        self.manifest
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Device and inode of a file with more than one name, identifying it among its hardlinks.
+#[cfg(unix)]
+fn hardlink_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hardlink_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
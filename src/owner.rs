@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use uzers::{Groups, Users, UsersCache};
+
+/// Owner and group of a backed up entry, by id and, where the backed up host knows them, by
+/// name.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl Owner {
+    /// Owner and group of an entry, with names looked up through `names`.
+    #[cfg(unix)]
+    pub fn of(metadata: &Metadata, names: &UsersCache) -> Option<Owner> {
+        use std::os::unix::fs::MetadataExt;
+
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        Some(Owner {
+            uid,
+            gid,
+            user: names.get_user_by_uid(uid).and_then(|user| user.name().to_str().map(str::to_string)),
+            group: names.get_group_by_gid(gid).and_then(|group| group.name().to_str().map(str::to_string)),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &Metadata, _names: &UsersCache) -> Option<Owner> {
+        None
+    }
+}
+
+/// How owners and groups recorded in a backup are given back to restored entries.
+#[derive(Parser, Debug)]
+pub struct OwnerParams {
+    #[arg(long = "numeric-owner", help = "restore owners and groups by their recorded ids instead of looking up their names")]
+    pub numeric: bool,
+    #[arg(long = "map-uid", value_parser = parse_mapping, help = "give what user id FROM owned to user id TO, as FROM:TO, ahead of any name")]
+    pub uids: Vec<(u32, u32)>,
+    #[arg(long = "map-gid", value_parser = parse_mapping, help = "give what group id FROM owned to group id TO, as FROM:TO, ahead of any name")]
+    pub gids: Vec<(u32, u32)>,
+}
+
+impl OwnerParams {
+    pub fn map(&self) -> OwnerMap {
+        OwnerMap {
+            enabled: uzers::get_effective_uid() == 0,
+            numeric: self.numeric,
+            uids: self.uids.iter().copied().collect(),
+            gids: self.gids.iter().copied().collect(),
+            names: UsersCache::new(),
+        }
+    }
+}
+
+fn parse_mapping(value: &str) -> Result<(u32, u32), String> {
+    let (from, to) = value.split_once(':').ok_or_else(|| format!("{value} isn't of the form FROM:TO"))?;
+    let id = |id: &str| id.parse::<u32>().map_err(|e| format!("{id} isn't an id: {e}"));
+    Ok((id(from)?, id(to)?))
+}
+
+/// Maps recorded owners to ids on the restoring host: by the `--map-uid` and `--map-gid`
+/// mappings first, then by name unless restoring numerically, and by the recorded id
+/// otherwise. Only root can give files away, so for anyone else nothing is mapped and
+/// restored entries keep belonging to whoever restores them.
+pub struct OwnerMap {
+    enabled: bool,
+    numeric: bool,
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+    names: UsersCache,
+}
+
+impl OwnerMap {
+    /// The user and group id a restored entry owned by `owner` should get, if any.
+    pub fn resolve(&self, owner: &Owner) -> Option<(u32, u32)> {
+        if !self.enabled {
+            return None;
+        }
+        let uid = match self.uids.get(&owner.uid) {
+            Some(uid) => *uid,
+            None => owner.user
+                .as_deref()
+                .filter(|_| !self.numeric)
+                .and_then(|name| self.names.get_user_by_name(name))
+                .map_or(owner.uid, |user| user.uid()),
+        };
+        let gid = match self.gids.get(&owner.gid) {
+            Some(gid) => *gid,
+            None => owner.group
+                .as_deref()
+                .filter(|_| !self.numeric)
+                .and_then(|name| self.names.get_group_by_name(name))
+                .map_or(owner.gid, |group| group.gid()),
+        };
+        Some((uid, gid))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// The mapping the restore options `args` give, as if restoring as root.
+    fn map(args: &[&str]) -> OwnerMap {
+        let mut map = OwnerParams::try_parse_from(["test"].iter().chain(args)).unwrap().map();
+        map.enabled = true;
+        map
+    }
+
+    fn owner(user: &str, group: &str) -> Owner {
+        Owner { uid: 1234, gid: 5678, user: Some(user.to_string()), group: Some(group.to_string()) }
+    }
+
+    #[test]
+    fn resolves_names_ahead_of_ids() {
+        assert_eq!(map(&[]).resolve(&owner("root", "root")), Some((0, 0)));
+        assert_eq!(map(&[]).resolve(&owner("no-such-user", "no-such-group")), Some((1234, 5678)));
+        let unnamed = Owner { user: None, group: None, ..owner("root", "root") };
+        assert_eq!(map(&[]).resolve(&unnamed), Some((1234, 5678)));
+    }
+
+    #[test]
+    fn resolves_ids_when_numeric() {
+        assert_eq!(map(&["--numeric-owner"]).resolve(&owner("root", "root")), Some((1234, 5678)));
+    }
+
+    #[test]
+    fn maps_ids_ahead_of_names() {
+        let map = map(&["--map-uid", "1234:42", "--map-gid", "1:2"]);
+        assert_eq!(map.resolve(&owner("root", "root")), Some((42, 0)));
+        assert_eq!(map.resolve(&Owner { gid: 1, ..owner("root", "root") }), Some((42, 2)));
+        assert!(OwnerParams::try_parse_from(["test", "--map-uid", "1234"]).is_err());
+    }
+
+    #[test]
+    fn maps_nothing_unless_root() {
+        let map = OwnerMap { enabled: false, ..map(&["--map-uid", "1234:42"]) };
+        assert_eq!(map.resolve(&owner("root", "root")), None);
+    }
+}
@@ -0,0 +1,461 @@
+use std::fs::{self, Metadata};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use tar::{Archive, EntryType, GnuExtSparseHeader, Header, HeaderMode};
+use uzers::UsersCache;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+use crate::archive::{copy_bounded, entry_path, open_source, set_metadata, walk_tree, ArchiveOptions, ArchiveSummary, Codec, Compression};
+use crate::owner::{Owner, OwnerMap};
+use crate::sparse::{extents_len, ExtentReader};
+
+/// Prefix of the pax records holding extended attributes, as written by GNU tar and star.
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Longest path the name field of a ustar header holds by itself.
+const USTAR_NAME_SIZE: usize = 100;
+
+/// How `tar_dir` stores an entry `select` keeps.
+pub enum TarEntry {
+    /// The entry itself, with the data regions of a sparse file, if any.
+    Data(Vec<(u64, u64)>),
+    /// A hardlink to the file stored earlier in the archive under this name.
+    Link(String),
+}
+
+/// Archives the tree under `src` into `dst` as a pax tar stream compressed as a whole with
+/// `options.compression`. Unlike a zip archive it keeps each entry's mode, owner, group and
+/// mtime, symlinks as links, and extended attributes, and sparse files are stored as GNU
+/// sparse entries any tar can restore. `select` is called like for `zip_dir`, but returns how
+/// to store the entries to keep.
+pub fn tar_dir<W, F>(src: &str, dst: W, options: &ArchiveOptions, mut select: F) -> io::Result<ArchiveSummary>
+where
+    W: Write,
+    F: FnMut(&str, &Path, &Metadata) -> io::Result<Option<TarEntry>>,
+{
+    if !Path::new(src).is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{src} is not a directory")));
+    }
+
+    let mut builder = tar::Builder::new(Encoder::new(dst, options.compression)?);
+    let mut summary = ArchiveSummary::default();
+    let mut buffer = vec![0; options.buffer_size.max(1)];
+    let names = UsersCache::new();
+    walk_tree(src, options.walk, |name, path, metadata| {
+        let Some(source) = open_source(path, metadata, true)? else {
+            return Ok(());
+        };
+        let (extents, link) = match select(name, path, metadata)? {
+            Some(TarEntry::Data(extents)) => (extents, None),
+            Some(TarEntry::Link(target)) => (Vec::new(), Some(target)),
+            None => return Ok(()),
+        };
+
+        let file_type = metadata.file_type();
+        if !(file_type.is_file() || file_type.is_dir() || file_type.is_symlink()) {
+            return Ok(());
+        }
+        // Only GNU headers can describe sparse files.
+        let mut header = match extents.is_empty() {
+            true => Header::new_ustar(),
+            false => Header::new_gnu(),
+        };
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+        header.set_mode(header.mode()? & 0o7777);
+        header.set_size(0);
+        if let Some(owner) = Owner::of(metadata, &names) {
+            // Names too long for the header are left out, so those entries are restored by id.
+            let _ = owner.user.map(|user| header.set_username(&user));
+            let _ = owner.group.map(|group| header.set_groupname(&group));
+        }
+        let mut records = match link {
+            Some(_) => Vec::new(),
+            None => xattr_records(source.xattrs),
+        };
+        // The header only holds whole seconds.
+        if let Ok(modified) = metadata.modified()?.duration_since(UNIX_EPOCH) {
+            if modified.subsec_nanos() != 0 {
+                records.push(("mtime".to_string(), format!("{}.{:09}", modified.as_secs(), modified.subsec_nanos()).into_bytes()));
+            }
+        }
+        set_path(&mut header, &mut records, "path", name, |header, name| header.set_path(name))?;
+
+        if let Some(target) = &link {
+            header.set_entry_type(EntryType::Link);
+            set_path(&mut header, &mut records, "linkpath", target, |header, name| header.set_link_name(name))?;
+        } else if let Some(target) = &source.target {
+            set_path(&mut header, &mut records, "linkpath", target, |header, name| header.set_link_name(name))?;
+        }
+        append_pax_records(&mut builder, name, &records)?;
+
+        match source.file {
+            Some(file) if header.entry_type() == EntryType::Regular && !extents.is_empty() => {
+                let size = extents_len(&extents);
+                let extensions = set_sparse_map(&mut header, &extents, metadata.len());
+                header.set_size(size);
+                header.set_cksum();
+                builder.append(&header, io::empty())?;
+                for extension in &extensions {
+                    builder.get_mut().write_all(extension.as_bytes())?;
+                }
+                let copied = copy_bounded(&mut ExtentReader::new(file, extents), builder.get_mut(), &mut buffer)?;
+                pad_entry(builder.get_mut(), copied)?;
+                summary.add(metadata.len(), false);
+            }
+            Some(file) if header.entry_type() == EntryType::Regular => {
+                header.set_size(metadata.len());
+                header.set_cksum();
+                let mut file = file.take(metadata.len());
+                builder.append(&header, io::empty())?;
+                let copied = copy_bounded(&mut file, builder.get_mut(), &mut buffer)?;
+                if copied != metadata.len() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{name} shrank while being archived")));
+                }
+                pad_entry(builder.get_mut(), copied)?;
+                summary.add(metadata.len(), false);
+            }
+            _ => {
+                header.set_cksum();
+                builder.append(&header, io::empty())?;
+            }
+        }
+        Ok(())
+    })?;
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(summary)
+}
+
+/// Sets a path field of `header` with `set`, falling back to a pax record named `key` for
+/// paths too long for the field.
+fn set_path<F>(header: &mut Header, records: &mut Vec<(String, Vec<u8>)>, key: &str, path: &str, set: F) -> io::Result<()>
+where
+    F: Fn(&mut Header, &str) -> io::Result<()>,
+{
+    if set(header, path).is_err() {
+        records.push((key.to_string(), path.as_bytes().to_vec()));
+        set(header, truncate(path))?;
+    }
+    Ok(())
+}
+
+/// The start of `path` that fits the name field of a ustar header.
+fn truncate(path: &str) -> &str {
+    let mut end = path.len().min(USTAR_NAME_SIZE - 1);
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    &path[..end]
+}
+
+/// Writes `records` as the pax extended header of the entry `name` that follows it.
+fn append_pax_records<W: Write>(builder: &mut tar::Builder<W>, name: &str, records: &[(String, Vec<u8>)]) -> io::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // Each record starts with its length in decimal, which counts its own digits.
+        let rest = key.len() + value.len() + 3;
+        let mut len = rest + 1;
+        while len != rest + len.to_string().len() {
+            len = rest + len.to_string().len();
+        }
+        data.extend_from_slice(format!("{len} {key}=").as_bytes());
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    let base = Path::new(name).file_name().and_then(|base| base.to_str()).unwrap_or_default();
+    header.set_path(truncate(&format!("PaxHeaders/{base}")))?;
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    builder.append(&header, data.as_slice())
+}
+
+/// Makes `header` a GNU sparse entry of a file `size` bytes long made of the data regions
+/// `extents`, and returns the extension headers holding the regions that don't fit in it,
+/// which go between the header and the data.
+fn set_sparse_map(header: &mut Header, extents: &[(u64, u64)], size: u64) -> Vec<GnuExtSparseHeader> {
+    header.set_entry_type(EntryType::GNUSparse);
+    let gnu = header.as_gnu_mut().expect("sparse files get GNU headers");
+    let (first, rest) = extents.split_at(extents.len().min(gnu.sparse.len()));
+    for (block, (offset, len)) in gnu.sparse.iter_mut().zip(first) {
+        block.set_offset(*offset);
+        block.set_length(*len);
+    }
+    gnu.set_real_size(size);
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut extensions = Vec::new();
+    for (index, extents) in rest.chunks(GnuExtSparseHeader::new().sparse.len()).enumerate() {
+        let mut extension = GnuExtSparseHeader::new();
+        for (block, (offset, len)) in extension.sparse.iter_mut().zip(extents) {
+            block.set_offset(*offset);
+            block.set_length(*len);
+        }
+        extension.set_is_extended((index + 1) * extension.sparse.len() < rest.len());
+        extensions.push(extension);
+    }
+    extensions
+}
+
+/// Pads an entry's data of `len` bytes to the 512 byte blocks tar is made of.
+fn pad_entry<W: Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    let padding = (512 - len % 512) % 512;
+    writer.write_all(&[0; 512][..padding as usize])
+}
+
+fn xattr_records(xattrs: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+    xattrs.into_iter().map(|(key, value)| (format!("{XATTR_PREFIX}{key}"), value)).collect()
+}
+
+fn not_unicode(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is not valid unicode", path.display()))
+}
+
+/// A directory whose metadata is applied once everything inside it has been restored, so
+/// restoring its contents doesn't change its mtime and a read-only mode doesn't get in the way.
+struct Dir {
+    path: PathBuf,
+    header: Header,
+    modified: SystemTime,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Restores entries of tar archives made by `tar_dir` under a directory, reapplying their
+/// mode, mtime, extended attributes and owner and group as mapped by `owners`.
+pub struct Unpacker {
+    dst: PathBuf,
+    xattrs: bool,
+    owners: OwnerMap,
+    dirs: Vec<Dir>,
+}
+
+impl Unpacker {
+    /// Without `xattrs`, extended attributes in the archive are left out.
+    pub fn new(dst: &str, xattrs: bool, owners: OwnerMap) -> Self {
+        Unpacker { dst: PathBuf::from(dst), xattrs, owners, dirs: Vec::new() }
+    }
+
+    /// Restores the entries of `archive` `select` returns a name for, under that name, and
+    /// returns how many there were. With a `limit`, the rest of the archive isn't read once
+    /// that many were restored. Directories are only created; their metadata is applied by
+    /// `finish`.
+    pub fn unpack<R, F>(&mut self, archive: R, compression: Compression, limit: Option<usize>, mut select: F) -> io::Result<usize>
+    where
+        R: Read,
+        F: FnMut(&str) -> Option<String>,
+    {
+        let mut archive = Archive::new(Decoder::new(archive, compression)?);
+        let mut count = 0;
+        for entry in archive.entries()? {
+            if limit.is_some_and(|limit| count >= limit) {
+                break;
+            }
+            let mut entry = entry?;
+            let name = entry.path()?;
+            let name = name.to_str().ok_or_else(|| not_unicode(&name))?.trim_end_matches('/').to_string();
+            let Some(output) = select(&name) else { continue };
+            count += 1;
+
+            let path = entry_path(&self.dst, &output)?;
+            let records: Vec<(String, Vec<u8>)> = match entry.pax_extensions()? {
+                Some(extensions) => extensions
+                    .filter_map(|extension| {
+                        let extension = extension.ok()?;
+                        Some((extension.key().ok()?.to_string(), extension.value_bytes().to_vec()))
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            let xattrs = records
+                .iter()
+                .filter(|_| self.xattrs)
+                .filter_map(|(key, value)| Some((key.strip_prefix(XATTR_PREFIX)?.to_string(), value.clone())))
+                .collect();
+            let modified = match records.iter().find(|(key, _)| key == "mtime").and_then(|(_, value)| pax_time(value)) {
+                Some(modified) => modified,
+                None => UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?),
+            };
+            if entry.header().entry_type() == EntryType::Directory {
+                fs::create_dir_all(&path)?;
+                self.dirs.push(Dir { path, header: entry.header().clone(), modified, xattrs });
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if matches!(entry.header().entry_type(), EntryType::Symlink | EntryType::Link) {
+                // Links aren't replaced by unpacking, so whatever is in the way is removed first.
+                let _ = fs::remove_file(&path);
+            }
+            entry.set_preserve_permissions(true);
+            entry.set_preserve_mtime(true);
+            entry.set_unpack_xattrs(false);
+            if output != name {
+                // Only regular files are restored under another name, where nothing can point
+                // outside the restore directory.
+                entry.unpack(&path)?;
+            } else if !entry.unpack_in(&self.dst)? {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{name} points outside the restore directory")));
+            }
+            let owner = self.owner(entry.header())?;
+            apply_metadata(&path, entry.header(), owner, modified, &xattrs)?;
+        }
+        Ok(count)
+    }
+
+    /// Applies the metadata of the restored directories, innermost first.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.dirs.sort_by(|a, b| b.path.cmp(&a.path));
+        for dir in &self.dirs {
+            apply_metadata(&dir.path, &dir.header, self.owner(&dir.header)?, dir.modified, &dir.xattrs)?;
+        }
+        Ok(())
+    }
+
+    /// The ids to give an entry with `header`, if any.
+    fn owner(&self, header: &Header) -> io::Result<Option<(u32, u32)>> {
+        let (Ok(uid), Ok(gid)) = (u32::try_from(header.uid()?), u32::try_from(header.gid()?)) else {
+            return Ok(None);
+        };
+        let name = |name: Result<Option<&str>, _>| name.ok().flatten().filter(|name| !name.is_empty()).map(str::to_string);
+        let owner = Owner { uid, gid, user: name(header.username()), group: name(header.groupname()) };
+        Ok(self.owners.resolve(&owner))
+    }
+}
+
+/// Reapplies `owner`, `modified`, `xattrs` and the mode in `header` to a restored entry, since
+/// unpacking leaves the mtime of links alone and changing the owner clears setuid bits.
+fn apply_metadata(
+    path: &Path,
+    header: &Header,
+    owner: Option<(u32, u32)>,
+    modified: SystemTime,
+    xattrs: &[(String, Vec<u8>)],
+) -> io::Result<()> {
+    set_metadata(path, owner, Some(header.mode()?), modified, xattrs)
+}
+
+/// The time in a pax `mtime` record, seconds since the epoch with an optional fraction.
+fn pax_time(value: &[u8]) -> Option<SystemTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)]).parse().ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Compresses a tar stream as a whole.
+enum Encoder<W: Write> {
+    Store(W),
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Lz4(FrameEncoder<W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(inner: W, compression: Compression) -> io::Result<Self> {
+        let level = compression.level;
+        Ok(match compression.codec {
+            Codec::Store => Encoder::Store(inner),
+            Codec::Deflate => Encoder::Gzip(GzEncoder::new(
+                inner,
+                level.map_or_else(flate2::Compression::default, |level| flate2::Compression::new(level as u32)),
+            )),
+            Codec::Bzip2 => Encoder::Bzip2(BzEncoder::new(
+                inner,
+                level.map_or_else(bzip2::Compression::default, |level| bzip2::Compression::new(level as u32)),
+            )),
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?),
+            Codec::Lz4 => Encoder::Lz4(FrameEncoder::new(inner)),
+            Codec::Xz => Encoder::Xz(XzEncoder::new(inner, level.unwrap_or(6) as u32)),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Store(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => encoder.finish().map_err(io::Error::from),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Store(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Bzip2(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Store(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Bzip2(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompresses a tar stream written through an `Encoder`.
+enum Decoder<R: Read> {
+    Store(R),
+    Gzip(GzDecoder<R>),
+    Bzip2(BzDecoder<R>),
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+    Lz4(FrameDecoder<R>),
+    Xz(XzDecoder<R>),
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(inner: R, compression: Compression) -> io::Result<Self> {
+        Ok(match compression.codec {
+            Codec::Store => Decoder::Store(inner),
+            Codec::Deflate => Decoder::Gzip(GzDecoder::new(inner)),
+            Codec::Bzip2 => Decoder::Bzip2(BzDecoder::new(inner)),
+            Codec::Zstd => Decoder::Zstd(zstd::Decoder::new(inner)?),
+            Codec::Lz4 => Decoder::Lz4(FrameDecoder::new(inner)),
+            Codec::Xz => Decoder::Xz(XzDecoder::new(inner)),
+        })
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Store(inner) => inner.read(buf),
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            Decoder::Bzip2(decoder) => decoder.read(buf),
+            Decoder::Zstd(decoder) => decoder.read(buf),
+            Decoder::Lz4(decoder) => decoder.read(buf),
+            Decoder::Xz(decoder) => decoder.read(buf),
+        }
+    }
+}
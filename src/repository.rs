@@ -0,0 +1,260 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use fastcdc::v2020::StreamCDC;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::BoxError;
+use crate::archive::{create_symlink, entry_path, remove_symlink, set_metadata, walk_tree, warn_vanished, WalkOptions};
+use crate::crypto::{check, open, seal, Keys, KEY_SIZE};
+use crate::download::DownloadConfig;
+use crate::manifest::{EntryKind, Manifest, ManifestBuilder, ManifestEntry};
+use crate::owner::OwnerMap;
+use crate::retry::{with_retries, RETRY_DELAY};
+use crate::sparse::{ExtentReader, ExtentWriter};
+use crate::storage::Storage;
+
+/// Prefix all chunks are stored under, shared by every snapshot in the bucket so identical
+/// chunks are only ever stored once.
+pub const CHUNK_PREFIX: &str = "chunks/";
+
+const MIN_CHUNK_SIZE: u32 = 512 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Key of the chunk named `hash` by `chunk_hash`.
+pub fn chunk_key(hash: &str) -> String {
+    format!("{CHUNK_PREFIX}{}/{hash}", &hash[..2])
+}
+
+/// Name of the chunk holding `data`: its HMAC-SHA256 keyed with `secret`, so names don't reveal
+/// which known contents a bucket holds to anyone without the keys, or else its SHA-256. Either
+/// way identical chunks get the same name and are stored once.
+fn chunk_hash(secret: Option<&[u8; KEY_SIZE]>, data: &[u8]) -> String {
+    match secret {
+        Some(secret) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+            mac.update(data);
+            hex::encode(mac.finalize().into_bytes())
+        }
+        None => format!("{:x}", Sha256::digest(data)),
+    }
+}
+
+/// Whether `data` is the contents of the chunk named `hash`. Chunks stored before names were
+/// keyed are named by their SHA-256, and keys without a secret to check keyed names with have
+/// already made sure of it by decrypting the chunk, which is bound to its name.
+fn is_chunk(keys: Option<&Keys>, secret: Option<&[u8; KEY_SIZE]>, hash: &str, data: &[u8]) -> bool {
+    chunk_hash(None, data) == hash
+        || match (keys, secret) {
+            (Some(_), Some(secret)) => chunk_hash(Some(secret), data) == hash,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+}
+
+/// Stores the tree under `src` as content-defined chunks, uploading only chunks the bucket
+/// doesn't hold yet, and returns the snapshot's manifest. Files `builder` considers unchanged
+/// since the parent snapshot keep its chunk list without being read again, and symlinks and
+/// the other names of hardlinked files are only recorded in the manifest. Chunks are encrypted
+/// with `keys` if given, and named by a hash keyed with their secret if they have one.
+pub async fn backup_to_repository(
+    storage: &Arc<dyn Storage>,
+    src: &str,
+    mut builder: ManifestBuilder<'_>,
+    keys: Option<Arc<Keys>>,
+    walk: &WalkOptions,
+    concurrency: usize,
+    retries: u32,
+) -> Result<Manifest, BoxError> {
+    let mut files = Vec::new();
+    walk_tree(src, walk, |name, path, metadata| {
+        if builder.select(name, path, metadata)? && metadata.is_file() {
+            files.push((name.to_string(), path.to_path_buf(), builder.extents(name)));
+        }
+        Ok(())
+    })?;
+
+    let mut known = stored_chunks(storage, keys.as_deref()).await?;
+    for (name, path, extents) in files {
+        let file = match File::open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn_vanished(&path);
+                builder.forget(&name);
+                continue;
+            }
+            file => file?,
+        };
+        let chunks = store_file(storage, file, extents, &mut known, &keys, concurrency.max(1), retries).await?;
+        builder.set_chunks(&name, chunks);
+    }
+    Ok(builder.finish())
+}
+
+/// Hashes of all chunks already in the bucket. Since new snapshots reuse them, one of them
+/// is checked to be readable with `keys` so snapshots of a bucket don't end up mixing keys.
+async fn stored_chunks(storage: &Arc<dyn Storage>, keys: Option<&Keys>) -> Result<HashSet<String>, BoxError> {
+    let stored = storage.list(CHUNK_PREFIX).await?;
+    if let Some(key) = stored.first() {
+        check(keys, key, storage.get(key).await?)
+            .map_err(|e| format!("the repository in this bucket can't be extended with the given key: {e}"))?;
+    }
+    Ok(stored
+        .into_iter()
+        .filter_map(|key| key.rsplit('/').next().map(str::to_string))
+        .collect())
+}
+
+/// Splits `file`, or just its data regions `extents` if there are any, into chunks on a
+/// blocking thread while uploading the new ones, with at most `concurrency` chunks in flight,
+/// and returns the file's chunk hashes in order.
+async fn store_file(
+    storage: &Arc<dyn Storage>,
+    file: File,
+    extents: Vec<(u64, u64)>,
+    known: &mut HashSet<String>,
+    keys: &Option<Arc<Keys>>,
+    concurrency: usize,
+    retries: u32,
+) -> Result<Vec<String>, BoxError> {
+    let secret = keys.as_deref().map(Keys::chunk_secret).transpose()?.flatten();
+    let (sender, mut receiver) = mpsc::channel(concurrency);
+    let chunker = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let file: Box<dyn Read> = match extents.is_empty() {
+            true => Box::new(file),
+            false => Box::new(ExtentReader::new(file, extents)),
+        };
+        for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            if sender.blocking_send(chunk?.data).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let mut hashes = Vec::new();
+    let mut uploads = JoinSet::new();
+    while let Some(data) = receiver.recv().await {
+        let hash = chunk_hash(secret.as_ref(), &data);
+        if known.insert(hash.clone()) {
+            while uploads.len() >= concurrency {
+                uploads.join_next().await.unwrap()??;
+            }
+            let storage = storage.clone();
+            let keys = keys.clone();
+            let key = chunk_key(&hash);
+            uploads.spawn(async move {
+                let data = seal(keys.as_deref(), &key, data)?;
+                with_retries(retries, RETRY_DELAY, || storage.put(&key, data.clone())).await
+            });
+        }
+        hashes.push(hash);
+    }
+    chunker.await??;
+    while let Some(result) = uploads.join_next().await {
+        result??;
+    }
+    Ok(hashes)
+}
+
+/// Restores the entries of a repository snapshot under `path`, or only `file` if given.
+pub async fn restore_from_repository(
+    storage: &Arc<dyn Storage>,
+    manifest: &Manifest,
+    path: &str,
+    file: Option<&str>,
+    keys: Option<Arc<Keys>>,
+    owners: &OwnerMap,
+    config: DownloadConfig,
+) -> Result<(), BoxError> {
+    let DownloadConfig { concurrency, retries, .. } = config;
+    if let Some(file) = file {
+        if !manifest.entries.get(file).is_some_and(|entry| entry.kind != EntryKind::Dir) {
+            return Err(format!("{file} is not a file in this snapshot").into());
+        }
+    }
+    let mut dirs = Vec::new();
+    for (name, entry) in &manifest.entries {
+        if file.map_or(entry.link.is_some(), |file| file != name) {
+            continue;
+        }
+        let output = entry_path(Path::new(path), name)?;
+        let owner = entry.owner.as_ref().and_then(|owner| owners.resolve(owner));
+        match entry.kind {
+            EntryKind::Dir => {
+                fs::create_dir_all(&output)?;
+                dirs.push((output, owner, entry));
+            }
+            EntryKind::File => {
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                remove_symlink(&output)?;
+                // A single hardlinked file is restored from the name holding its chunks.
+                let source = entry.link.as_ref().and_then(|link| manifest.entries.get(link)).unwrap_or(entry);
+                restore_chunks(storage, source, &output, &keys, concurrency.max(1), retries).await?;
+                set_metadata(&output, owner, entry.mode, entry.modified(), &entry.xattrs()?)?;
+            }
+            EntryKind::Symlink => {
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                create_symlink(entry.target.as_deref().unwrap_or_default(), &output)?;
+                set_metadata(&output, owner, None, entry.modified(), &entry.xattrs()?)?;
+            }
+        }
+    }
+    if file.is_none() {
+        manifest.restore_hardlinks(Path::new(path))?;
+    }
+    // Restoring a directory's contents changes its mtime, so directories come last, innermost
+    // first.
+    for (output, owner, entry) in dirs.into_iter().rev() {
+        set_metadata(&output, owner, entry.mode, entry.modified(), &entry.xattrs()?)?;
+    }
+    Ok(())
+}
+
+/// Writes the chunks of `entry` to `output` in order, into its data regions for a sparse
+/// file, fetching up to `concurrency` of them ahead and rejecting any chunk whose contents
+/// don't match its hash.
+async fn restore_chunks(
+    storage: &Arc<dyn Storage>,
+    entry: &ManifestEntry,
+    output: &Path,
+    keys: &Option<Arc<Keys>>,
+    concurrency: usize,
+    retries: u32,
+) -> Result<(), BoxError> {
+    let mut output = ExtentWriter::new(File::create(output)?, entry.extents.clone());
+    let secret = keys.as_deref().map(Keys::chunk_secret).transpose()?.flatten();
+    let mut pending = VecDeque::new();
+    let mut next = entry.chunks.iter();
+    loop {
+        while pending.len() < concurrency {
+            let Some(hash) = next.next() else { break };
+            let storage = storage.clone();
+            let keys = keys.clone();
+            let hash = hash.clone();
+            pending.push_back(tokio::spawn(async move {
+                let key = chunk_key(&hash);
+                let data = with_retries(retries, RETRY_DELAY, || storage.get(&key)).await?;
+                let data = open(keys.as_deref(), &key, data)?;
+                if !is_chunk(keys.as_deref(), secret.as_ref(), &hash, &data) {
+                    return Err(format!("chunk {hash} is corrupted").into());
+                }
+                Ok::<_, BoxError>(data)
+            }));
+        }
+        match pending.pop_front() {
+            Some(chunk) => output.write_all(&chunk.await??)?,
+            None => return Ok(output.finish()?),
+        }
+    }
+}
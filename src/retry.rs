@@ -0,0 +1,78 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::BoxError;
+
+/// Delay before the first retry of a failed request.
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Runs `op` until it succeeds or has failed `retries + 1` times, doubling the delay between
+/// attempts starting from `initial_delay`.
+pub async fn with_retries<T, E, F, Fut>(retries: u32, initial_delay: Duration, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output=Result<T, E>>,
+{
+    let mut delay = initial_delay;
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(_) if attempt < retries => {
+                attempt += 1;
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// One of the independent requests `run_all` makes, such as fetching or uploading part of
+/// an object.
+#[async_trait]
+pub trait Request: Send + Sync + 'static {
+    type Output: Send + 'static;
+
+    async fn run(&self) -> Result<Self::Output, BoxError>;
+}
+
+/// Makes `requests`, up to `concurrency` at once and retrying each independently, and calls
+/// `done` with the output of each as it finishes. The first request to fail for good aborts
+/// the rest.
+pub async fn run_all<R, F>(
+    requests: impl IntoIterator<Item=R>,
+    concurrency: usize,
+    retries: u32,
+    mut done: F,
+) -> Result<(), BoxError>
+where
+    R: Request,
+    F: FnMut(R::Output) -> Result<(), BoxError>,
+{
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for request in requests {
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            with_retries(retries, RETRY_DELAY, || request.run()).await
+        });
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        match result? {
+            Ok(output) => done(output)?,
+            Err(e) => {
+                tasks.abort_all();
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,306 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::vec;
+
+/// Alignment of data regions, the block size of tar archives, which need every region but
+/// the last to span whole blocks.
+const ALIGNMENT: u64 = 512;
+
+/// Data regions of the sparse file at `path`, as (offset, length) pairs in order, or none if
+/// it has no holes. A file ending in a hole gets an empty region at its end, so the last
+/// region always ends where the file does. Files whose blocks cover their whole length have
+/// no holes and aren't looked at.
+pub fn data_extents(path: &Path, metadata: &Metadata) -> io::Result<Vec<(u64, u64)>> {
+    if !metadata.is_file() || allocated(metadata) >= metadata.len() {
+        return Ok(Vec::new());
+    }
+    let len = metadata.len();
+    let file = File::open(path)?;
+    let mut extents: Vec<(u64, u64)> = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let Some(start) = seek_data(&file, offset)? else { break };
+        let end = seek_hole(&file, start)?.min(len);
+        let start = start / ALIGNMENT * ALIGNMENT;
+        let end = match end % ALIGNMENT {
+            0 => end,
+            rest => end.saturating_add(ALIGNMENT - rest).min(len),
+        };
+        match extents.last_mut() {
+            Some((last, last_len)) if *last + *last_len >= start => *last_len = end - *last,
+            _ => extents.push((start, end - start)),
+        }
+        offset = end;
+    }
+    match extents.last() {
+        Some((0, all)) if *all == len => return Ok(Vec::new()),
+        Some((last, last_len)) if last + last_len == len => {}
+        _ => extents.push((len, 0)),
+    }
+    Ok(extents)
+}
+
+/// Number of bytes of data `extents` hold.
+pub fn extents_len(extents: &[(u64, u64)]) -> u64 {
+    extents.iter().map(|(_, len)| len).sum()
+}
+
+#[cfg(unix)]
+fn allocated(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::blocks(metadata).saturating_mul(512)
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Start of the first data region at or after `offset`, or `None` if there's only a hole
+/// left.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_data(file: &File, offset: u64) -> io::Result<Option<u64>> {
+    match lseek(file, offset, libc::SEEK_DATA) {
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Start of the first hole at or after `offset`; the end of the file counts as one.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_hole(file: &File, offset: u64) -> io::Result<u64> {
+    lseek(file, offset, libc::SEEK_HOLE)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn lseek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let offset = libc::off_t::try_from(offset).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: lseek only moves the offset of a descriptor `file` keeps open.
+    match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        position => Ok(position as u64),
+    }
+}
+
+// Without SEEK_DATA, files look like they are all data.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn seek_data(_file: &File, offset: u64) -> io::Result<Option<u64>> {
+    Ok(Some(offset))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn seek_hole(file: &File, _offset: u64) -> io::Result<u64> {
+    Ok(file.metadata()?.len())
+}
+
+/// Reads the data regions of a sparse file back to back.
+pub struct ExtentReader {
+    file: File,
+    extents: vec::IntoIter<(u64, u64)>,
+    remaining: u64,
+}
+
+impl ExtentReader {
+    pub fn new(file: File, extents: Vec<(u64, u64)>) -> Self {
+        ExtentReader { file, extents: extents.into_iter(), remaining: 0 }
+    }
+}
+
+impl Read for ExtentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let Some((offset, len)) = self.extents.next() else { return Ok(0) };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = len;
+        }
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.file.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being read"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Writes data read by an `ExtentReader` back into its regions, leaving holes in between.
+/// Without any regions, the data is written as is.
+pub struct ExtentWriter {
+    file: File,
+    extents: vec::IntoIter<(u64, u64)>,
+    remaining: u64,
+    /// Length of a sparse file, where its last region ends.
+    size: Option<u64>,
+    /// Whether the data is the whole file, holes and all, rather than just its regions.
+    whole: bool,
+    position: u64,
+}
+
+impl ExtentWriter {
+    pub fn new(file: File, extents: Vec<(u64, u64)>) -> Self {
+        let size = extents.last().map(|(offset, len)| offset + len);
+        ExtentWriter { file, extents: extents.into_iter(), remaining: 0, size, whole: false, position: 0 }
+    }
+
+    /// Like `new`, for data holding the whole file: the zeros in between `extents` are skipped
+    /// over instead of written, so they end up as holes again.
+    pub fn whole(file: File, extents: Vec<(u64, u64)>) -> Self {
+        ExtentWriter { whole: true, ..ExtentWriter::new(file, extents) }
+    }
+
+    /// Checks all regions of a sparse file got their data and extends it over any final hole.
+    pub fn finish(mut self) -> io::Result<()> {
+        let Some(size) = self.size else { return Ok(()) };
+        let short = match self.whole {
+            true => self.position < size,
+            false => self.remaining > 0 || self.extents.any(|(_, len)| len > 0),
+        };
+        if short {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse file data ended early"));
+        }
+        if self.whole && self.position > size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "sparse file has more data than its length"));
+        }
+        self.file.set_len(size)
+    }
+
+    /// Writes part of `buf`, which starts at `position` in the whole file, seeking over it
+    /// instead if it's zeros in a hole.
+    fn write_whole(&mut self, buf: &[u8]) -> io::Result<usize> {
+        while self.extents.as_slice().first().is_some_and(|(offset, len)| offset + len <= self.position) {
+            self.extents.next();
+        }
+        let written = match self.extents.as_slice().first() {
+            Some((offset, len)) if *offset <= self.position => {
+                let max = buf.len().min(usize::try_from(offset + len - self.position).unwrap_or(usize::MAX));
+                self.file.write(&buf[..max])?
+            }
+            next => {
+                let hole = next.map_or(u64::MAX, |(offset, _)| offset - self.position);
+                let max = buf.len().min(usize::try_from(hole).unwrap_or(usize::MAX));
+                match buf[..max].iter().all(|byte| *byte == 0) {
+                    true => {
+                        self.file.seek(SeekFrom::Current(max as i64))?;
+                        max
+                    }
+                    false => self.file.write(&buf[..max])?,
+                }
+            }
+        };
+        self.position += written as u64;
+        Ok(written)
+    }
+}
+
+impl Write for ExtentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size.is_none() {
+            return self.file.write(buf);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.whole {
+            return self.write_whole(buf);
+        }
+        while self.remaining == 0 {
+            let Some((offset, len)) = self.extents.next() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "sparse file has more data than its regions"));
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = len;
+        }
+        let max = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let written = self.file.write(&buf[..max])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// A 4 MiB file with some data at its start and in its middle, ending in a hole.
+    fn sparse_file(dir: &Path) -> PathBuf {
+        let path = dir.join("sparse");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"start").unwrap();
+        file.seek(SeekFrom::Start(2 * MIB)).unwrap();
+        file.write_all(&[1; 1000]).unwrap();
+        file.set_len(4 * MIB).unwrap();
+        path
+    }
+
+    #[test]
+    fn finds_data_regions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sparse_file(dir.path());
+        let extents = data_extents(&path, &path.metadata().unwrap()).unwrap();
+        assert_eq!(extents.first().map(|(offset, _)| *offset), Some(0));
+        assert!(extents.iter().any(|(offset, len)| *offset <= 2 * MIB && offset + len >= 2 * MIB + 1000));
+        assert_eq!(extents.last(), Some(&(4 * MIB, 0)));
+        assert!(extents_len(&extents) < MIB);
+    }
+
+    #[test]
+    fn leaves_dense_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dense");
+        fs::write(&path, vec![1; 100_000]).unwrap();
+        assert_eq!(data_extents(&path, &path.metadata().unwrap()).unwrap(), []);
+    }
+
+    #[test]
+    fn restores_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sparse_file(dir.path());
+        let extents = data_extents(&path, &path.metadata().unwrap()).unwrap();
+        let mut data = Vec::new();
+        ExtentReader::new(File::open(&path).unwrap(), extents.clone()).read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, extents_len(&extents));
+
+        let restored = dir.path().join("restored");
+        let mut writer = ExtentWriter::new(File::create(&restored).unwrap(), extents.clone());
+        io::copy(&mut data.as_slice(), &mut writer).unwrap();
+        writer.finish().unwrap();
+        assert!(fs::read(&restored).unwrap() == fs::read(&path).unwrap());
+        assert_eq!(data_extents(&restored, &restored.metadata().unwrap()).unwrap(), extents);
+    }
+
+    #[test]
+    fn restores_holes_from_whole_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = sparse_file(dir.path());
+        let extents = data_extents(&path, &path.metadata().unwrap()).unwrap();
+
+        let restored = dir.path().join("restored");
+        let mut writer = ExtentWriter::whole(File::create(&restored).unwrap(), extents.clone());
+        io::copy(&mut File::open(&path).unwrap(), &mut writer).unwrap();
+        writer.finish().unwrap();
+        assert!(fs::read(&restored).unwrap() == fs::read(&path).unwrap());
+        assert_eq!(data_extents(&restored, &restored.metadata().unwrap()).unwrap(), extents);
+    }
+
+    #[test]
+    fn rejects_data_not_filling_the_regions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ExtentWriter::new(File::create(dir.path().join("short")).unwrap(), vec![(0, 10), (100, 10)]);
+        writer.write_all(&[1; 15]).unwrap();
+        assert!(writer.finish().is_err());
+
+        let mut writer = ExtentWriter::new(File::create(dir.path().join("long")).unwrap(), vec![(0, 10)]);
+        assert!(writer.write_all(&[1; 15]).is_err());
+    }
+}
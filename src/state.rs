@@ -0,0 +1,162 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::download::DownloadProgress;
+use crate::storage::UploadProgress;
+
+/// What a `backup` run has finished so far, persisted in the state directory so a rerun
+/// with the same path, bucket, key and options continues from there instead of starting over.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupState {
+    #[serde(skip)]
+    file: PathBuf,
+    #[serde(skip)]
+    pub archive: PathBuf,
+    #[serde(skip)]
+    pub manifest: PathBuf,
+    /// Description of the options the archive is written with, other than secrets.
+    #[serde(default)]
+    pub options: String,
+    pub archive_complete: bool,
+    pub upload: Option<UploadProgress>,
+}
+
+impl BackupState {
+    /// Loads the state of a previous unfinished run of this backup, or starts a new one if
+    /// there is none, its archive has since disappeared or it was made with other `options`.
+    pub fn load_or_new(state_dir: &Path, path: &str, bucket: &str, key: &str, options: &str) -> io::Result<Self> {
+        let source = fs::canonicalize(path)?;
+        let id = state_id(&format!("s3://{bucket}/{key}?source={}", source.display()));
+        let file = state_dir.join(format!("{id}.json"));
+        let archive = state_dir.join(format!("{id}.zip"));
+        let manifest = state_dir.join(format!("{id}.manifest.json"));
+
+        if let Some(state) = load::<BackupState>(state_dir, &file)? {
+            if archive.is_file() && state.options == options {
+                return Ok(BackupState { file, archive, manifest, ..state });
+            }
+        }
+        Ok(BackupState {
+            archive,
+            manifest,
+            options: options.to_string(),
+            file,
+            archive_complete: false,
+            upload: None,
+        })
+    }
+
+    /// Forgets the archive written so far, and any upload of it, so they are made again.
+    pub fn restart(&mut self) {
+        self.archive_complete = false;
+        self.upload = None;
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        save(&self.file, self)
+    }
+
+    /// Deletes the state file, the archive and its manifest once the backup has been uploaded.
+    pub fn remove(self) -> io::Result<()> {
+        remove(&self.archive)?;
+        remove(&self.manifest)?;
+        remove(&self.file)
+    }
+}
+
+/// Where a plain copy of the manifest of the snapshot `key` is kept, for hosts whose keys can
+/// only encrypt and so can't read it back from the bucket when it's used as a parent.
+pub fn manifest_cache(state_dir: &Path, bucket: &str, key: &str) -> PathBuf {
+    state_dir.join("manifests").join(format!("{}.json", state_id(&format!("s3://{bucket}/{key}"))))
+}
+
+/// How far a `restore` run got downloading its archive, so a rerun for the same bucket and
+/// key only fetches the missing ranges.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreState {
+    #[serde(skip)]
+    file: PathBuf,
+    #[serde(skip)]
+    pub archive: PathBuf,
+    pub download: Option<DownloadProgress>,
+}
+
+impl RestoreState {
+    pub fn load_or_new(state_dir: &Path, bucket: &str, key: &str) -> io::Result<Self> {
+        let id = state_id(&format!("s3://{bucket}/{key}?restore"));
+        let file = state_dir.join(format!("{id}.json"));
+        let archive = state_dir.join(format!("{id}.zip"));
+
+        if let Some(state) = load::<RestoreState>(state_dir, &file)? {
+            return Ok(RestoreState { file, archive, ..state });
+        }
+        Ok(RestoreState {
+            archive,
+            file,
+            download: None,
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        save(&self.file, self)
+    }
+
+    /// Deletes the state file and the downloaded archive once the restore has finished.
+    pub fn remove(self) -> io::Result<()> {
+        remove(&self.archive)?;
+        remove(&self.file)
+    }
+}
+
+/// Replaces `file` with `contents`, readable by the current user only.
+pub fn write_private(file: &Path, contents: &[u8]) -> io::Result<()> {
+    create_dir(file.parent().unwrap())?;
+    remove(file)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(file)?.write_all(contents)
+}
+
+fn state_id(name: &str) -> Uuid {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes())
+}
+
+/// Creates `dir` and any missing parents readable by the current user only, as it holds
+/// plain archives and manifests of the files backed up.
+pub fn create_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+fn load<T: DeserializeOwned>(state_dir: &Path, file: &Path) -> io::Result<Option<T>> {
+    create_dir(state_dir)?;
+    match fs::read(file) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the state atomically, so a crash mid-write never leaves a corrupt state file.
+fn save<T: Serialize>(file: &Path, state: &T) -> io::Result<()> {
+    let temp = file.with_extension("json.tmp");
+    fs::write(&temp, serde_json::to_vec(state)?)?;
+    fs::rename(temp, file)
+}
+
+fn remove(file: &Path) -> io::Result<()> {
+    match fs::remove_file(file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use walkdir::WalkDir;
+
+use crate::BoxError;
+use super::{BucketSettings, ByteReader, Checkpoint, Storage, UploadConfig, UploadProgress};
+
+/// Stores every object as a file under `root`, e.g. a directory on a mounted NAS.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    /// Maps `key` to a path below `root`, refusing keys that would escape it.
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key {key:?}")));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Creates the directory `key` goes in and returns the temporary path to write it to
+    /// along with its final path, so it can be renamed into place once complete.
+    async fn prepare(&self, key: &str) -> io::Result<(PathBuf, PathBuf)> {
+        let destination = self.path(key)?;
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut temp = destination.clone().into_os_string();
+        temp.push(".partial");
+        Ok((temp.into(), destination))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn create_if_not_exists(&self, _settings: &BucketSettings) -> Result<(), BoxError> {
+        Ok(tokio::fs::create_dir_all(&self.root).await?)
+    }
+
+    /// The tag is derived from the modification time and size, which is what changes when
+    /// the file is overwritten.
+    async fn object_info(&self, key: &str) -> Result<Option<(u64, Option<String>)>, BoxError> {
+        let metadata = match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Some((metadata.len(), Some(format!("{}-{}", modified.as_nanos(), metadata.len())))))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64, e_tag: Option<&str>) -> Result<ByteReader, BoxError> {
+        if let Some(e_tag) = e_tag {
+            if self.object_info(key).await?.and_then(|(_, tag)| tag).as_deref() != Some(e_tag) {
+                return Err(format!("{key} changed while it was being read").into());
+            }
+        }
+        let mut file = tokio::fs::File::open(self.path(key)?).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::new(file.take(end - start)))
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BoxError> {
+        let (temp, destination) = self.prepare(key).await?;
+        tokio::fs::write(&temp, data).await?;
+        tokio::fs::rename(&temp, &destination).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BoxError> {
+        tokio::fs::remove_file(self.path(key)?).await?;
+        Ok(())
+    }
+
+    /// Walks only the directory the prefix points into, since keys map directly to paths.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BoxError> {
+        let directory = match prefix.rsplit_once('/') {
+            Some((directory, _)) => self.path(directory)?,
+            None => self.root.clone(),
+        };
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        let keys = tokio::task::spawn_blocking(move || {
+            WalkDir::new(directory)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .filter_map(|entry| {
+                    let relative = entry.path().strip_prefix(&root).ok()?;
+                    let key = relative.components()
+                        .map(|c| c.as_os_str().to_str())
+                        .collect::<Option<Vec<_>>>()?
+                        .join("/");
+                    (key.starts_with(&prefix) && !key.ends_with(".partial")).then_some(key)
+                })
+                .collect()
+        }).await?;
+        Ok(keys)
+    }
+
+    /// Copies `file` next to its destination and renames it into place, so the key never
+    /// holds a partial copy. There is nothing worth resuming, so `progress` is left alone.
+    async fn upload_file(
+        &self,
+        key: &str,
+        file: &Path,
+        _config: UploadConfig,
+        _progress: &mut Option<UploadProgress>,
+        _checkpoint: Checkpoint<'_, UploadProgress>,
+    ) -> Result<(), BoxError> {
+        let (temp, destination) = self.prepare(key).await?;
+        tokio::fs::copy(file, &temp).await?;
+        tokio::fs::rename(&temp, &destination).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use clap::{Parser, ValueEnum};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::BoxError;
+use crate::config::ConfigFile;
+
+pub use self::local::LocalStorage;
+pub use self::multipart::{UploadConfig, UploadProgress};
+pub use self::s3::{S3Settings, S3Storage};
+
+mod local;
+mod multipart;
+mod s3;
+
+/// A stream of object bytes returned by `Storage::get_range`.
+pub type ByteReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Called by long running operations after each step that would not need repeating if the
+/// operation were interrupted and started again with the same progress.
+pub type Checkpoint<'a, T> = &'a mut (dyn FnMut(&T) -> Result<(), BoxError> + Send);
+
+/// Where backups are stored. Keys are `/` separated paths relative to the bucket or
+/// directory the storage was opened on.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Creates the bucket or directory backups are stored in if it doesn't exist yet.
+    async fn create_if_not_exists(&self, settings: &BucketSettings) -> Result<(), BoxError>;
+
+    /// Size of the object stored under `key` and a tag that changes whenever the object does,
+    /// or `None` if there is no such object. Any other failure, like being denied access, is
+    /// an error.
+    async fn object_info(&self, key: &str) -> Result<Option<(u64, Option<String>)>, BoxError>;
+
+    /// Streams bytes `start..end` of `key`, failing if its tag no longer matches `e_tag`.
+    async fn get_range(&self, key: &str, start: u64, end: u64, e_tag: Option<&str>) -> Result<ByteReader, BoxError>;
+
+    /// Reads the whole object stored under `key`. Meant for small objects such as manifests.
+    async fn get(&self, key: &str) -> Result<Vec<u8>, BoxError> {
+        let (size, e_tag) = self.object_info(key).await?.ok_or_else(|| not_found(key))?;
+        let mut data = Vec::with_capacity(size as usize);
+        self.get_range(key, 0, size, e_tag.as_deref()).await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Stores `data` under `key`, replacing whatever was there.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BoxError>;
+
+    /// Deletes the object stored under `key`.
+    async fn delete(&self, key: &str) -> Result<(), BoxError>;
+
+    /// Keys of all objects whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BoxError>;
+
+    /// Stores `file` under `key`. Work already recorded in `progress` is skipped and
+    /// `checkpoint` is called as more gets done, so an interrupted upload can be resumed.
+    async fn upload_file(
+        &self,
+        key: &str,
+        file: &Path,
+        config: UploadConfig,
+        progress: &mut Option<UploadProgress>,
+        checkpoint: Checkpoint<'_, UploadProgress>,
+    ) -> Result<(), BoxError>;
+
+    /// Discards whatever an unfinished upload recorded in `progress` left behind.
+    async fn abort_upload(&self, _key: &str, _progress: &UploadProgress) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+/// The error for an object `key` that has to exist but doesn't.
+pub fn not_found(key: &str) -> BoxError {
+    format!("{key} does not exist").into()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// an S3 bucket
+    S3,
+    /// a local or mounted directory
+    Local,
+}
+
+#[derive(Parser, Debug)]
+pub struct StorageParams {
+    #[arg(short = 'b', help = "bucket name, or directory path for local storage")]
+    pub bucket: String,
+    #[arg(long = "storage", value_enum, default_value_t = Backend::S3, help = "where backups are stored")]
+    pub backend: Backend,
+    #[arg(long = "region", env = "AWS_BACKUP_REGION", help = "S3 region, detected from the bucket if not set")]
+    pub region: Option<String>,
+    #[arg(long = "endpoint-url", env = "AWS_BACKUP_ENDPOINT_URL", help = "URL of an S3 compatible service")]
+    pub endpoint_url: Option<String>,
+    #[arg(long = "path-style", env = "AWS_BACKUP_PATH_STYLE", help = "address buckets as part of the path instead of the host name")]
+    pub path_style: bool,
+    #[arg(long = "profile", env = "AWS_BACKUP_PROFILE", help = "named AWS profile to take credentials and region from")]
+    pub profile: Option<String>,
+    #[arg(long = "config", env = "AWS_BACKUP_CONFIG", help = "config file with defaults for the S3 options")]
+    pub config: Option<PathBuf>,
+}
+
+/// Protections applied to a bucket when `backup` has to create it. Existing buckets are
+/// left as they are.
+#[derive(Parser, Debug, Default)]
+pub struct BucketSettings {
+    #[arg(long = "block-public-access", help = "block all public access to a newly created bucket")]
+    pub block_public_access: bool,
+    #[arg(long = "default-encryption", help = "enable SSE-S3 default encryption on a newly created bucket")]
+    pub default_encryption: bool,
+    #[arg(long = "versioning", help = "enable versioning on a newly created bucket")]
+    pub versioning: bool,
+}
+
+impl StorageParams {
+    pub async fn open(&self) -> Result<Arc<dyn Storage>, BoxError> {
+        Ok(match self.backend {
+            Backend::S3 => Arc::new(S3Storage::new(&self.bucket, &self.s3_settings()?).await?),
+            Backend::Local => Arc::new(LocalStorage::new(PathBuf::from(&self.bucket))),
+        })
+    }
+
+    /// Flags and environment variables take precedence over the config file.
+    fn s3_settings(&self) -> Result<S3Settings, BoxError> {
+        let file = ConfigFile::load(self.config.as_deref())?;
+        Ok(S3Settings {
+            region: self.region.clone().or(file.region),
+            endpoint_url: self.endpoint_url.clone().or(file.endpoint_url),
+            path_style: self.path_style || file.path_style.unwrap_or(false),
+            profile: self.profile.clone().or(file.profile),
+        })
+    }
+}
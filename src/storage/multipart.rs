@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use aws_sdk_s3::Client;
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::types::ByteStream;
+use aws_smithy_http::byte_stream::Length;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::BoxError;
+use crate::retry::{run_all, Request};
+
+/// S3 rejects parts smaller than this, except for the last one.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// S3 rejects parts, and objects sent with a single `PutObject`, larger than this.
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// S3 rejects uploads with more parts than this.
+pub const MAX_PARTS: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct UploadConfig {
+    pub part_size: u64,
+    pub concurrency: usize,
+    pub retries: u32,
+}
+
+impl UploadConfig {
+    /// Part size actually used for a file of `size` bytes, grown if needed to stay within
+    /// S3's part size and part count limits.
+    pub fn effective_part_size(&self, size: u64) -> u64 {
+        self.part_size
+            .max(MIN_PART_SIZE)
+            .max((size + MAX_PARTS - 1) / MAX_PARTS)
+            .min(MAX_PART_SIZE)
+    }
+}
+
+/// An in-flight multipart upload and the parts S3 has already acknowledged, keyed by part
+/// number. This is what gets persisted so an interrupted upload can be picked up again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadProgress {
+    pub upload_id: String,
+    pub part_size: u64,
+    pub parts: BTreeMap<i32, String>,
+}
+
+pub async fn create_multipart_upload(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    part_size: u64,
+) -> Result<UploadProgress, BoxError> {
+    let upload_id = client
+        .create_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .send()
+        .await?
+        .upload_id()
+        .ok_or("multipart upload was created without an upload id")?
+        .to_string();
+    Ok(UploadProgress { upload_id, part_size, parts: BTreeMap::new() })
+}
+
+/// Asks S3 which parts of `upload_id` it actually holds, so a resumed upload never trusts a
+/// stale local record. Fails if the upload has been aborted or has expired.
+pub async fn list_uploaded_parts(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<BTreeMap<i32, String>, BoxError> {
+    let mut parts = BTreeMap::new();
+    let mut marker = None;
+    loop {
+        let output = client
+            .list_parts()
+            .bucket(bucket_name)
+            .key(key)
+            .upload_id(upload_id)
+            .set_part_number_marker(marker)
+            .send()
+            .await?;
+        for part in output.parts().unwrap_or_default() {
+            if let Some(e_tag) = part.e_tag() {
+                parts.insert(part.part_number(), e_tag.to_string());
+            }
+        }
+        if !output.is_truncated() {
+            return Ok(parts);
+        }
+        marker = output.next_part_number_marker().map(str::to_string);
+    }
+}
+
+pub async fn abort_multipart_upload(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<(), BoxError> {
+    client
+        .abort_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Uploads every part of `file_name` missing from `progress`, sending up to
+/// `config.concurrency` parts at once and retrying each part independently, then completes
+/// the upload. `checkpoint` is called after each acknowledged part.
+pub async fn upload_multipart<F>(
+    client: &Client,
+    bucket_name: &str,
+    file_name: &Path,
+    key: &str,
+    config: UploadConfig,
+    progress: &mut UploadProgress,
+    checkpoint: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&UploadProgress) -> Result<(), BoxError>,
+{
+    upload_parts(client, bucket_name, file_name, key, config, progress, checkpoint).await?;
+
+    let parts = progress.parts
+        .iter()
+        .map(|(number, e_tag)| CompletedPart::builder()
+            .e_tag(e_tag)
+            .part_number(*number)
+            .build())
+        .collect();
+    let completed = CompletedMultipartUpload::builder()
+        .set_parts(Some(parts))
+        .build();
+    client
+        .complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(&progress.upload_id)
+        .multipart_upload(completed)
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn upload_parts<F>(
+    client: &Client,
+    bucket_name: &str,
+    file_name: &Path,
+    key: &str,
+    config: UploadConfig,
+    progress: &mut UploadProgress,
+    mut checkpoint: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&UploadProgress) -> Result<(), BoxError>,
+{
+    let size = tokio::fs::metadata(file_name).await?.len();
+    let part_size = progress.part_size;
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    let mut number = 1;
+    while offset < size {
+        let length = part_size.min(size - offset);
+        if !progress.parts.contains_key(&number) {
+            parts.push(Part {
+                client: client.clone(),
+                bucket_name: bucket_name.to_string(),
+                key: key.to_string(),
+                upload_id: progress.upload_id.clone(),
+                file_name: file_name.to_path_buf(),
+                number,
+                offset,
+                length,
+            });
+        }
+        offset += length;
+        number += 1;
+    }
+
+    run_all(parts, config.concurrency, config.retries, |(number, e_tag)| {
+        progress.parts.insert(number, e_tag);
+        checkpoint(progress)
+    })
+    .await
+}
+
+struct Part {
+    client: Client,
+    bucket_name: String,
+    key: String,
+    upload_id: String,
+    file_name: PathBuf,
+    number: i32,
+    offset: u64,
+    length: u64,
+}
+
+#[async_trait]
+impl Request for Part {
+    type Output = (i32, String);
+
+    async fn run(&self) -> Result<(i32, String), BoxError> {
+        // Streamed from the file, so parts are never held in memory whole.
+        let body = ByteStream::read_from()
+            .path(&self.file_name)
+            .offset(self.offset)
+            .length(Length::Exact(self.length))
+            .build()
+            .await?;
+        let output = self.client
+            .upload_part()
+            .bucket(&self.bucket_name)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .part_number(self.number)
+            .body(body)
+            .send()
+            .await?;
+        let e_tag = output.e_tag().ok_or("uploaded part has no ETag")?;
+        Ok((self.number, e_tag.to_string()))
+    }
+}
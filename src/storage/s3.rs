@@ -0,0 +1,306 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use aws_config::profile::{ProfileFileCredentialsProvider, ProfileFileRegionProvider};
+use aws_sdk_s3::{Client, Region};
+use aws_sdk_s3::error::PutObjectError;
+use aws_sdk_s3::model::{
+    BucketLocationConstraint, BucketVersioningStatus, CreateBucketConfiguration, PublicAccessBlockConfiguration,
+    ServerSideEncryption, ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration, ServerSideEncryptionRule,
+    VersioningConfiguration,
+};
+use aws_sdk_s3::output::PutObjectOutput;
+use aws_sdk_s3::types::{ByteStream, SdkError};
+
+use crate::BoxError;
+use super::{BucketSettings, ByteReader, Checkpoint, Storage};
+use super::multipart::{
+    abort_multipart_upload, create_multipart_upload, list_uploaded_parts, upload_multipart, UploadConfig,
+    UploadProgress, MAX_PART_SIZE,
+};
+
+/// How to reach the S3 service; anything left unset falls back to the AWS SDK's own
+/// environment and profile based defaults.
+#[derive(Clone, Debug, Default)]
+pub struct S3Settings {
+    pub region: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub path_style: bool,
+    pub profile: Option<String>,
+}
+
+pub struct S3Storage {
+    client: Client,
+    region: String,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub async fn new(bucket: &str, settings: &S3Settings) -> Result<Self, BoxError> {
+        let (client, region) = get_client(bucket, settings).await?;
+        Ok(S3Storage { client, region, bucket: bucket.to_string() })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn create_if_not_exists(&self, settings: &BucketSettings) -> Result<(), BoxError> {
+        create_bucket_if_not_exists(&self.client, &self.bucket, &self.region, settings).await
+    }
+
+    async fn object_info(&self, key: &str) -> Result<Option<(u64, Option<String>)>, BoxError> {
+        let output = match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+            Ok(output) => output,
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some((output.content_length().max(0) as u64, output.e_tag().map(str::to_string))))
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64, e_tag: Option<&str>) -> Result<ByteReader, BoxError> {
+        // HTTP ranges can't be empty, and an empty object has nothing to read anyway.
+        if start >= end {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let output = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", start, end - 1))
+            .set_if_match(e_tag.map(str::to_string))
+            .send()
+            .await?;
+        Ok(Box::new(output.body.into_async_read()))
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), BoxError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BoxError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, BoxError> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let output = self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token)
+                .send()
+                .await?;
+            let contents = output.contents().unwrap_or_default();
+            keys.extend(contents.iter().filter_map(|object| object.key()).map(str::to_string));
+            continuation_token = output.next_continuation_token().map(str::to_string);
+            if continuation_token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    /// Small files are sent with a single `PutObject`; anything larger than one part, or than
+    /// S3 takes in one request, becomes a multipart upload, resumed from `progress` if S3 still
+    /// has it.
+    async fn upload_file(
+        &self,
+        key: &str,
+        file: &Path,
+        config: UploadConfig,
+        progress: &mut Option<UploadProgress>,
+        checkpoint: Checkpoint<'_, UploadProgress>,
+    ) -> Result<(), BoxError> {
+        let size = tokio::fs::metadata(file).await?.len();
+        let part_size = config.effective_part_size(size);
+        if size <= part_size.min(MAX_PART_SIZE) {
+            upload_object(&self.client, &self.bucket, file, key).await?;
+            return Ok(());
+        }
+
+        let resumed = match progress.take() {
+            Some(mut previous) if previous.part_size == part_size => {
+                match list_uploaded_parts(&self.client, &self.bucket, key, &previous.upload_id).await {
+                    Ok(parts) => {
+                        previous.parts = parts;
+                        Some(previous)
+                    }
+                    Err(_) => None,
+                }
+            }
+            Some(stale) => {
+                let _ = abort_multipart_upload(&self.client, &self.bucket, key, &stale.upload_id).await;
+                None
+            }
+            None => None,
+        };
+        let progress = match resumed {
+            Some(resumed) => progress.insert(resumed),
+            None => progress.insert(create_multipart_upload(&self.client, &self.bucket, key, part_size).await?),
+        };
+        checkpoint(progress)?;
+
+        upload_multipart(&self.client, &self.bucket, file, key, config, progress, checkpoint).await
+    }
+
+    async fn abort_upload(&self, key: &str, progress: &UploadProgress) -> Result<(), BoxError> {
+        abort_multipart_upload(&self.client, &self.bucket, key, &progress.upload_id).await
+    }
+}
+
+/// Region assumed when none is configured and the bucket's location can't be looked up,
+/// e.g. because it doesn't exist yet.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Builds a client for `bucket` and returns it along with the region it talks to.
+async fn get_client(bucket: &str, settings: &S3Settings) -> Result<(Client, String), BoxError> {
+    let mut loader = aws_config::from_env();
+    if let Some(profile) = &settings.profile {
+        loader = loader
+            .credentials_provider(ProfileFileCredentialsProvider::builder().profile_name(profile).build())
+            .region(ProfileFileRegionProvider::builder().profile_name(profile).build());
+    }
+    if let Some(region) = &settings.region {
+        loader = loader.region(Region::new(region.clone()));
+    }
+    let shared_config = loader.load().await;
+
+    let builder = || {
+        let builder = aws_sdk_s3::config::Builder::from(&shared_config)
+            .force_path_style(settings.path_style);
+        match &settings.endpoint_url {
+            Some(endpoint_url) => builder.endpoint_url(endpoint_url),
+            None => builder,
+        }
+    };
+    let region = match shared_config.region() {
+        Some(region) => region.as_ref().to_string(),
+        None => {
+            let probe = Client::from_conf(builder().region(Region::new(DEFAULT_REGION)).build());
+            bucket_region(&probe, bucket).await.unwrap_or_else(|| DEFAULT_REGION.to_string())
+        }
+    };
+    let config = builder().region(Region::new(region.clone())).build();
+    Ok((Client::from_conf(config), region))
+}
+
+/// Looks up the region a bucket lives in. S3 reports us-east-1 as an empty location and
+/// eu-west-1 as the legacy `EU`.
+async fn bucket_region(client: &Client, bucket: &str) -> Option<String> {
+    let output = client.get_bucket_location().bucket(bucket).send().await.ok()?;
+    Some(match output.location_constraint().map(|location| location.as_str()) {
+        None | Some("") => DEFAULT_REGION.to_string(),
+        Some("EU") => "eu-west-1".to_string(),
+        Some(region) => region.to_string(),
+    })
+}
+
+pub async fn upload_object(
+    client: &Client,
+    bucket_name: &str,
+    file_name: &Path,
+    key: &str,
+) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
+    let body = ByteStream::from_path(file_name).await;
+    client
+        .put_object()
+        .bucket(bucket_name)
+        .key(key)
+        .body(body.unwrap())
+        .send()
+        .await
+}
+
+/// Creates `bucket_name` in `region` unless it already exists, and applies `settings` to it
+/// if it was created. A bucket that exists but can't be accessed is reported as an error
+/// rather than mistaken for a missing one.
+pub async fn create_bucket_if_not_exists(
+    client: &Client,
+    bucket_name: &str,
+    region: &str,
+    settings: &BucketSettings,
+) -> Result<(), BoxError> {
+    match client.head_bucket().bucket(bucket_name).send().await {
+        Ok(_) => return Ok(()),
+        Err(SdkError::ServiceError(e)) if e.err().is_not_found() => {}
+        Err(SdkError::ServiceError(e)) if e.raw().http().status().as_u16() == 403 => {
+            return Err(format!("access to bucket {bucket_name} is denied; it may be owned by another account").into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut request = client.create_bucket().bucket(bucket_name);
+    // us-east-1 is the one region S3 rejects as an explicit location constraint.
+    if region != DEFAULT_REGION {
+        request = request.create_bucket_configuration(
+            CreateBucketConfiguration::builder()
+                .location_constraint(BucketLocationConstraint::from(region))
+                .build(),
+        );
+    }
+    match request.send().await {
+        Ok(_) => {}
+        Err(SdkError::ServiceError(e)) if e.err().is_bucket_already_owned_by_you() => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    if settings.block_public_access {
+        client
+            .put_public_access_block()
+            .bucket(bucket_name)
+            .public_access_block_configuration(
+                PublicAccessBlockConfiguration::builder()
+                    .block_public_acls(true)
+                    .ignore_public_acls(true)
+                    .block_public_policy(true)
+                    .restrict_public_buckets(true)
+                    .build(),
+            )
+            .send()
+            .await?;
+    }
+    if settings.default_encryption {
+        let rule = ServerSideEncryptionRule::builder()
+            .apply_server_side_encryption_by_default(
+                ServerSideEncryptionByDefault::builder()
+                    .sse_algorithm(ServerSideEncryption::Aes256)
+                    .build(),
+            )
+            .build();
+        client
+            .put_bucket_encryption()
+            .bucket(bucket_name)
+            .server_side_encryption_configuration(
+                ServerSideEncryptionConfiguration::builder().rules(rule).build(),
+            )
+            .send()
+            .await?;
+    }
+    if settings.versioning {
+        client
+            .put_bucket_versioning()
+            .bucket(bucket_name)
+            .versioning_configuration(
+                VersioningConfiguration::builder()
+                    .status(BucketVersioningStatus::Enabled)
+                    .build(),
+            )
+            .send()
+            .await?;
+    }
+    Ok(())
+}